@@ -0,0 +1,179 @@
+use playerstate::{Cell, ROWS, COLS};
+
+pub type Board = [[Cell; COLS]; ROWS];
+
+// Where every column of every board comes from when the board cube turns a
+// quarter. The boards are stacked front to back in player order, so turning
+// the cube lays the columns of all boards out along the depth axis: reading
+// the new columns in order, they come from each player's last column in turn,
+// then each player's second to last, and so on. That holds for any number of
+// players, it just takes more or fewer columns from each board.
+pub struct ColumnMap {
+    players: usize,
+    // indexed by player * COLS + column of the new boards, the player and
+    // column of the old boards it's taken from
+    sources: Vec<(usize, usize)>,
+}
+
+impl ColumnMap {
+    pub fn quarter_turn(players: usize) -> ColumnMap {
+        let sources = (0..players * COLS)
+            .map(|k| (k % players, COLS - 1 - k / players))
+            .collect();
+        let map = ColumnMap {
+            players: players,
+            sources: sources,
+        };
+        debug_assert!(map.is_permutation());
+        map
+    }
+
+    pub fn source(&self, player: usize, col: usize) -> (usize, usize) {
+        self.sources[player * COLS + col]
+    }
+
+    // the map that puts every column back where it was
+    pub fn inverse(&self) -> ColumnMap {
+        let mut sources = vec![(0, 0); self.sources.len()];
+        for (k, &(p, c)) in self.sources.iter().enumerate() {
+            sources[p * COLS + c] = (k / COLS, k % COLS);
+        }
+        ColumnMap {
+            players: self.players,
+            sources: sources,
+        }
+    }
+
+    pub fn apply(&self, boards: &Vec<Board>) -> Vec<Board> {
+        assert_eq!(boards.len(), self.players);
+        let mut out = vec![[[Cell::E; COLS]; ROWS]; self.players];
+        for p in 0..self.players {
+            for c in 0..COLS {
+                let (sp, sc) = self.source(p, c);
+                for r in 0..ROWS {
+                    out[p][r][c] = boards[sp][r][sc];
+                }
+            }
+        }
+        debug_assert_eq!(filled(&out), filled(boards));
+        out
+    }
+
+    // every old column is used exactly once
+    fn is_permutation(&self) -> bool {
+        let mut seen = vec![false; self.sources.len()];
+        for &(p, c) in self.sources.iter() {
+            if p >= self.players || c >= COLS || seen[p * COLS + c] {
+                return false;
+            }
+            seen[p * COLS + c] = true;
+        }
+        self.sources.len() == self.players * COLS
+    }
+}
+
+fn filled(boards: &Vec<Board>) -> usize {
+    boards.iter()
+        .map(|b| b.iter().map(|row| row.iter().filter(|&&c| c != Cell::E).count()).sum::<usize>())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // more players than any room will hold
+    const MAX_PLAYERS: usize = 2 * COLS;
+
+    // how the boards were turned before the map, player `me` taking column c
+    // from the returned player and column
+    fn old_source(players: usize, me: usize, c: usize) -> (usize, usize) {
+        let col_offset = me * COLS / players;
+        let state_start = me * COLS % players;
+        ((state_start + c) % players, COLS - 1 - col_offset - (state_start + c) / players)
+    }
+
+    // boards whose every column spells out its own player and column in
+    // filled and empty cells, so any column can be told from any other
+    fn labelled(players: usize) -> Vec<Board> {
+        let mut boards = vec![[[Cell::E; COLS]; ROWS]; players];
+        for p in 0..players {
+            for c in 0..COLS {
+                let label = p * COLS + c + 1;
+                for r in 0..ROWS {
+                    if label >> r & 1 == 1 {
+                        boards[p][r][c] = Cell::I;
+                    }
+                }
+            }
+        }
+        boards
+    }
+
+    fn turn(map: &ColumnMap, boards: &Vec<Board>, times: usize) -> Vec<Board> {
+        let mut out = boards.clone();
+        for _ in 0..times {
+            out = map.apply(&out);
+        }
+        out
+    }
+
+    #[test]
+    fn every_column_is_used_once() {
+        for n in 1..MAX_PLAYERS + 1 {
+            let map = ColumnMap::quarter_turn(n);
+            assert!(map.is_permutation(), "{} players", n);
+            let mut seen = vec![0; n * COLS];
+            for p in 0..n {
+                for c in 0..COLS {
+                    let (sp, sc) = map.source(p, c);
+                    seen[sp * COLS + sc] += 1;
+                }
+            }
+            assert!(seen.iter().all(|&k| k == 1), "{} players", n);
+        }
+    }
+
+    #[test]
+    fn matches_the_old_arithmetic() {
+        for n in 1..MAX_PLAYERS + 1 {
+            let map = ColumnMap::quarter_turn(n);
+            let boards = labelled(n);
+            let turned = map.apply(&boards);
+            for p in 0..n {
+                for c in 0..COLS {
+                    let (sp, sc) = old_source(n, p, c);
+                    assert_eq!(map.source(p, c), (sp, sc), "{} players", n);
+                    for r in 0..ROWS {
+                        assert_eq!(turned[p][r][c], boards[sp][r][sc]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn inverse_undoes_any_number_of_turns() {
+        for n in 1..MAX_PLAYERS + 1 {
+            let map = ColumnMap::quarter_turn(n);
+            let back = map.inverse();
+            let boards = labelled(n);
+            for times in 0..9 {
+                let turned = turn(&map, &boards, times);
+                assert!(turn(&back, &turned, times) == boards, "{} players, {} turns", n, times);
+            }
+        }
+    }
+
+    // one board, or as many boards as columns, make a true square and come
+    // back round in four turns. Other counts re-deal the columns across the
+    // boards and take longer to come back.
+    #[test]
+    fn four_turns_of_a_square_are_the_identity() {
+        for &n in [1, COLS].iter() {
+            let map = ColumnMap::quarter_turn(n);
+            let boards = labelled(n);
+            assert!(turn(&map, &boards, 4) == boards, "{} players", n);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::f32::consts::PI;
+
+use nalgebra::{Vector3, Isometry3};
+
+use playerstate::{PlayerState, ROWS, COLS};
+
+const DISTANCE: f32 = 31.0;
+const MIN_DISTANCE: f32 = 8.0;
+const MAX_DISTANCE: f32 = 80.0;
+const MAX_PITCH: f32 = PI / 2.0 * 0.95;
+// radians a frame the orbit turns by
+const ORBIT_SPEED: f32 = 0.005;
+// fraction of the way to the target covered each frame
+const EASE: f32 = 0.15;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum CameraMode {
+    // square on to the front board, as if it were flat
+    Front,
+    // from above and off to one side, showing all the boards at once
+    Isometric,
+    // slowly circling the boards
+    Orbit,
+    // close in on the falling piece of the front board
+    Follow,
+}
+
+impl CameraMode {
+    pub fn next(self) -> CameraMode {
+        match self {
+            CameraMode::Front => CameraMode::Isometric,
+            CameraMode::Isometric => CameraMode::Orbit,
+            CameraMode::Orbit => CameraMode::Follow,
+            CameraMode::Follow => CameraMode::Front,
+        }
+    }
+
+    // yaw, pitch and distance the mode starts from
+    fn preset(self) -> (f32, f32, f32) {
+        match self {
+            CameraMode::Front => (0.0, 0.0, DISTANCE),
+            CameraMode::Isometric => (PI / 4.0, -(0.5f32).sqrt().atan(), DISTANCE + 5.0),
+            CameraMode::Orbit => (0.0, -0.3, DISTANCE + 9.0),
+            CameraMode::Follow => (0.0, 0.0, DISTANCE / 2.0),
+        }
+    }
+}
+
+// where the boards are seen from. The boards are moved rather than the
+// camera, by these amounts, before the board rotation is applied.
+pub struct CameraRig {
+    pub mode: CameraMode,
+    // what the view is easing toward, mouse and keys move these
+    target: (f32, f32, f32),
+    pub yaw: f32,
+    pub pitch: f32,
+    pub distance: f32,
+    // point of the front board kept in the middle of the view, in cells
+    // from the board's centre
+    pub focus: (f32, f32),
+}
+
+impl CameraRig {
+    pub fn new() -> CameraRig {
+        let (yaw, pitch, distance) = CameraMode::Front.preset();
+        CameraRig {
+            mode: CameraMode::Front,
+            target: (yaw, pitch, distance),
+            yaw: yaw,
+            pitch: pitch,
+            distance: distance,
+            focus: (0.0, 0.0),
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: CameraMode) {
+        // unwind whole turns so the new mode doesn't spin round to reach
+        // its yaw
+        let turns = (self.yaw / (2.0 * PI)).round();
+        self.yaw -= turns * 2.0 * PI;
+        self.mode = mode;
+        self.target = mode.preset();
+    }
+
+    pub fn turn(&mut self, dyaw: f32, dpitch: f32) {
+        self.target.0 += dyaw;
+        self.target.1 = (self.target.1 + dpitch).max(-MAX_PITCH).min(MAX_PITCH);
+    }
+
+    // positive steps zoom in, each a tenth closer
+    pub fn zoom(&mut self, steps: f32) {
+        self.target.2 = (self.target.2 * (1.0 - steps * 0.1))
+            .max(MIN_DISTANCE).min(MAX_DISTANCE);
+    }
+
+    // eases toward the target, following the given player's piece if
+    // that's the mode
+    pub fn update(&mut self, front: Option<&PlayerState>) {
+        if self.mode == CameraMode::Orbit {
+            self.target.0 += ORBIT_SPEED;
+        }
+        let focus = match (self.mode, front) {
+            (CameraMode::Follow, Some(ps)) =>
+                (ps.tetro_pos.1 as f32 + 1.5 - (COLS as f32 / 2.0 - 0.5),
+                 ps.tetro_pos.0 as f32 + 1.5 - (ROWS as f32 / 2.0 - 0.5)),
+            _ => (0.0, 0.0),
+        };
+        self.yaw += (self.target.0 - self.yaw) * EASE;
+        self.pitch += (self.target.1 - self.pitch) * EASE;
+        self.distance += (self.target.2 - self.distance) * EASE;
+        self.focus.0 += (focus.0 - self.focus.0) * EASE;
+        self.focus.1 += (focus.1 - self.focus.1) * EASE;
+    }
+
+    // from board space to the camera's, given the boards' own rotation:
+    // back off, tilt, swing round, then turn the boards about the focus
+    pub fn transform(&self, orientation: &Isometry3<f32>) -> Isometry3<f32> {
+        let zero = Vector3::new(0.0, 0.0, 0.0);
+        Isometry3::new(Vector3::new(0.0, 0.0, self.distance), zero)
+            * Isometry3::new(zero, Vector3::new(self.pitch, 0.0, 0.0))
+            * Isometry3::new(zero, Vector3::new(0.0, self.yaw, 0.0))
+            * *orientation
+            * Isometry3::new(Vector3::new(-self.focus.0, -self.focus.1, 0.0), zero)
+    }
+}
@@ -0,0 +1,88 @@
+pub const CHAT_HISTORY: usize = 6;
+pub const CHAT_MAX_LEN: usize = 120;
+
+pub struct ChatLog {
+    lines: Vec<String>,
+}
+
+impl ChatLog {
+    pub fn new() -> ChatLog {
+        ChatLog {
+            lines: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, from: Option<usize>, text: &str) {
+        let sender = match from {
+            Some(id) => format!("P{}", id),
+            None => "spectator".to_string(),
+        };
+        self.add(format!("{}: {}", sender, text));
+    }
+
+    // something the game itself has to say, in among the chat
+    pub fn notice(&mut self, text: &str) {
+        self.add(format!("-- {}", text));
+    }
+
+    fn add(&mut self, line: String) {
+        self.lines.push(line);
+        if self.lines.len() > CHAT_HISTORY {
+            self.lines.remove(0);
+        }
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines[..]
+    }
+}
+
+// the text-entry line, game keys are suspended while it is open
+pub struct ChatEntry {
+    pub text: Option<String>,
+}
+
+impl ChatEntry {
+    pub fn new() -> ChatEntry {
+        ChatEntry {
+            text: None,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.text.is_some()
+    }
+
+    pub fn open(&mut self) {
+        self.text = Some(String::new());
+    }
+
+    pub fn cancel(&mut self) {
+        self.text = None;
+    }
+
+    pub fn push_char(&mut self, c: char) {
+        if let Some(ref mut text) = self.text {
+            if text.chars().count() < CHAT_MAX_LEN {
+                text.push(c);
+            }
+        }
+    }
+
+    pub fn backspace(&mut self) {
+        if let Some(ref mut text) = self.text {
+            text.pop();
+        }
+    }
+
+    // closes the entry line, returning the message if there is one to send
+    pub fn submit(&mut self) -> Option<String> {
+        match self.text.take() {
+            Some(text) => {
+                let text = text.trim().to_string();
+                if text.is_empty() { None } else { Some(text) }
+            },
+            None => None,
+        }
+    }
+}
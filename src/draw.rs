@@ -1,85 +1,239 @@
-use std::cmp;
 use std::f32;
 use std::rc::Rc;
-use std::cell::{RefCell};
+use std::cell::{RefCell, Cell as Shared};
+use std::io;
 use std::path::Path;
+use std::time::{Duration, Instant};
 
 use kiss3d::window::Window;
 use kiss3d::scene::SceneNode;
 use kiss3d::resource::material::Matrixerial;
+use kiss3d::resource::Mesh;
 use kiss3d::text::Font;
 
 use nalgebra::{Vector3, Isometry3, Point2, Point3, Rotation};
 
 use num::traits::One;
 
-use playerstate::{PlayerState, Shape, ISHAPE, JSHAPE, LSHAPE, OSHAPE,
-                  SSHAPE, TSHAPE, ZSHAPE, Cell, ROWS, COLS, TradeState};
+use playerstate::{PlayerState, Shape, Cell, ROWS, COLS, TradeState,
+                  TradeExpiry, shape_letter, cell_of_shape};
 
-use other_material::MyObjectMatrixerial;
+use other_material::{MyObjectMatrixerial, MaterialParams, ShaderSources};
+use netstats::NetHealth;
+use events::GameEvent;
+use theme::{Theme, Color};
+use camera::{CameraRig, CameraMode};
+use flat::FlatView;
+use raster;
+use effects::Effects;
+use picker::{TradePicker, CAROUSEL_SLOTS, PICKER_WIDTH, PICKER_TOP, PICKER_ROW};
 
-const CUBE_SIZE: f32 = 0.8;
+pub const CUBE_SIZE: f32 = 0.8;
+// how long the HUD names a theme after switching to it
+const THEME_NAME_MS: u64 = 2000;
 
-pub type Color = (f32, f32, f32);
+const FONT_PATH: &'static str = "./src/FreeSans.ttf";
+static EMBEDDED_FONT: &'static [u8] = include_bytes!("FreeSans.ttf");
 
-const ECOLOR: Color = (0.0, 0.0, 0.0);
-const ICOLOR: Color = (0.0, 1.0, 1.0);
-const JCOLOR: Color = (1.0, 1.0, 0.0);
-const LCOLOR: Color = (1.0, 0.647, 1.0);
-const OCOLOR: Color = (0.0, 0.0, 1.0);
-const SCOLOR: Color = (0.0, 1.0, 0.0);
-const TCOLOR: Color = (1.0, 0.0, 0.0);
-const ZCOLOR: Color = (0.5, 0.0, 0.5);
+// prefers the font next to the sources so it can be swapped out, falling
+// back to the copy built into the binary when run from elsewhere
+fn load_font(size: i32) -> Rc<Font> {
+    let path = Path::new(FONT_PATH);
+    if path.exists() {
+        Font::new(path, size)
+    }
+    else {
+        Font::from_memory(EMBEDDED_FONT, size)
+    }
+}
+
+// how the boards are laid out
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Layout {
+    // one behind the other in 3D, ours in front
+    Layered,
+    // side by side and square on
+    Flat,
+}
+
+// whose landing positions are shown
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum GhostMode {
+    Off,
+    Mine,
+    Everyone,
+}
+
+impl GhostMode {
+    pub fn next(self) -> GhostMode {
+        match self {
+            GhostMode::Off => GhostMode::Mine,
+            GhostMode::Mine => GhostMode::Everyone,
+            GhostMode::Everyone => GhostMode::Off,
+        }
+    }
+}
+
+// one persistent cube per visible cell of a player's board, recoloured and
+// shown or hidden only when that player's board changes
+struct BoardNodes {
+    cells: Vec<SceneNode>,
+    // each cell's pattern, when the theme has them
+    marks: Vec<Option<SceneNode>>,
+    last: Option<[[Cell; COLS]; ROWS]>,
+}
+
+pub type Material = Rc<RefCell<Box<Matrixerial>>>;
+
+// dots poking out through both faces of a cube, as a group under it
+pub fn add_marks(node: &mut SceneNode, dots: &[(f32, f32)], mat: &Material)
+                 -> Option<SceneNode> {
+    if dots.is_empty() {
+        return None;
+    }
+    let mut group = node.add_group();
+    for &(dx, dy) in dots.iter() {
+        let mut dot = group.add_cube(0.15, 0.15, CUBE_SIZE + 0.05);
+        dot.prepend_to_local_translation(&Vector3::new(dx, dy, 0.0));
+        dot.set_color(0.1, 0.1, 0.1);
+        dot.set_material(mat.clone());
+    }
+    Some(group)
+}
 
-fn tetro_color(s: Shape) -> Color {
-    match s {
-        ISHAPE => ICOLOR,
-        JSHAPE => JCOLOR,
-        LSHAPE => LCOLOR,
-        OSHAPE => OCOLOR,
-        SSHAPE => SCOLOR,
-        TSHAPE => TCOLOR,
-        ZSHAPE => ZCOLOR,
-        _ => ICOLOR,
+fn translucent(sources: &ShaderSources, theme: &Theme,
+               params: &Rc<Shared<MaterialParams>>) -> Material {
+    Rc::new(RefCell::new(Box::new(
+        MyObjectMatrixerial::new(sources, true, theme.peer_opacity, params.clone()))))
+}
+
+// the theme's lighting, keeping the edges as they were
+fn lit(params: MaterialParams, theme: &Theme) -> MaterialParams {
+    MaterialParams {
+        alpha: theme.lighting.alpha,
+        ambient: theme.lighting.ambient,
+        specular: theme.lighting.specular,
+        shininess: theme.lighting.shininess,
+        .. params
     }
 }
 
-fn cell_color(p: Cell) -> Color {
-    match p {
-        Cell::E => ECOLOR,
-        Cell::I => ICOLOR,
-        Cell::J => JCOLOR,
-        Cell::L => LCOLOR,
-        Cell::O => OCOLOR,
-        Cell::S => SCOLOR,
-        Cell::T => TCOLOR,
-        Cell::Z => ZCOLOR,
+// the grid as a single wireframe mesh, each segment a degenerate triangle
+fn grid_mesh() -> Mesh {
+    fn segment(coords: &mut Vec<Point3<f32>>, faces: &mut Vec<Point3<u32>>,
+               a: Point3<f32>, b: Point3<f32>) {
+        let i = coords.len() as u32;
+        coords.push(a);
+        coords.push(b);
+        faces.push(Point3::new(i, i + 1, i + 1));
+    }
+
+    let mut coords = Vec::new();
+    let mut faces = Vec::new();
+    for x in -(COLS as isize / 2)..(COLS as isize / 2 + 1) {
+        for z in -(COLS as isize / 2)..(COLS as isize / 2 + 1) {
+            segment(&mut coords, &mut faces,
+                    Point3::new(x as f32, -(ROWS as f32 / 2.0), z as f32),
+                    Point3::new(x as f32, (ROWS as f32 / 2.0) - 2.0, z as f32));
+        }
+    }
+    for y in -(ROWS as isize / 2)..((ROWS - 2) as isize / 2) {
+        for x in -(COLS as isize / 2)..(COLS as isize / 2 + 1) {
+            segment(&mut coords, &mut faces,
+                    Point3::new(x as f32, y as f32, -(COLS as f32 / 2.0)),
+                    Point3::new(x as f32, y as f32, COLS as f32 / 2.0));
+        }
+        for z in -(COLS as isize / 2)..(COLS as isize / 2 + 1) {
+            segment(&mut coords, &mut faces,
+                    Point3::new(COLS as f32 / 2.0, y as f32, z as f32),
+                    Point3::new(-(COLS as f32 / 2.0), y as f32, z as f32));
+        }
     }
+    let normals = vec![Vector3::new(0.0, 1.0, 0.0); coords.len()];
+    Mesh::new(coords, faces, Some(normals), None, false)
+}
+
+// position of a player in the id-ordered list of present players
+fn player_index(player_states: &Vec<PlayerState>, id: usize) -> usize {
+    player_states.iter().position(|ps| ps.id == id).unwrap_or(0)
 }
 
 pub struct Draw {
+    // the boards' own rotation, which board rotations animate
     pub orientation: Isometry3<f32>,
+    pub camera: CameraRig,
+    pub layout_mode: Layout,
+    flat: FlatView,
     pub board_grp: SceneNode,
+    // rebuilt every frame, pieces move too often to be worth keeping
     pub tetromino_grp: SceneNode,
-    pub translucent_mat: Rc<RefCell<Box<Matrixerial>>>,
-    pub opaque_mat: Rc<RefCell<Box<Matrixerial>>>,
+    cells_grp: SceneNode,
+    boards: Vec<BoardNodes>,
+    // player ids and viewer the cell nodes were laid out for
+    layout: (Vec<usize>, usize),
+    grid: SceneNode,
+    pub translucent_mat: Material,
+    pub opaque_mat: Material,
+    // lighting both materials read every frame
+    material: Rc<Shared<MaterialParams>>,
+    shaders: ShaderSources,
+    // the theme file's first if there is one, then the built-in ones
+    themes: Vec<Theme>,
+    theme: usize,
+    // when the theme last changed, for the HUD to name it for a while
+    theme_switched: Option<Instant>,
+    // player whose board is drawn in front when spectating
+    pub focus: usize,
+    pub show_netstats: bool,
+    pub ghost: GhostMode,
+    pub picker: TradePicker,
+    effects: Effects,
+    large_font: Rc<Font>,
+    font: Rc<Font>,
     anim_frames: u32,
     anim_frame_count: u32,
     anim_rot_vec: Vector3<f32>,
 }
 
 impl Draw {
-    pub fn new(window: &mut Window) -> Draw {
+    pub fn new(window: &mut Window, themes: Vec<Theme>) -> Draw {
         let mut bg = window.add_group();
         let tg = bg.add_group();
+        let cg = bg.add_group();
+        let mut grid = bg.add_mesh(Rc::new(RefCell::new(grid_mesh())),
+                                   Vector3::new(1.0, 1.0, 1.0));
+        let grid_color = themes[0].grid;
+        grid.set_color(grid_color.0, grid_color.1, grid_color.2);
+        grid.set_lines_width(1.0);
+        grid.set_surface_rendering_activation(false);
+        let material = Rc::new(Shared::new(lit(MaterialParams::default(), &themes[0])));
+        let shaders = ShaderSources::load();
         Draw {
             orientation: Isometry3::one(),
+            camera: CameraRig::new(),
+            layout_mode: Layout::Layered,
+            flat: FlatView::new(window),
             board_grp: bg,
             tetromino_grp: tg,
-            translucent_mat: Rc::new(RefCell::new(Box::new(
-                MyObjectMatrixerial::new(true)))),
+            cells_grp: cg,
+            boards: Vec::new(),
+            layout: (Vec::new(), 0),
+            grid: grid,
+            translucent_mat: translucent(&shaders, &themes[0], &material),
             opaque_mat: Rc::new(RefCell::new(Box::new(
-                MyObjectMatrixerial::new(false)))),
+                MyObjectMatrixerial::new(&shaders, false, 1.0, material.clone())))),
+            material: material,
+            shaders: shaders,
+            themes: themes,
+            theme: 0,
+            theme_switched: None,
+            focus: 0,
+            show_netstats: false,
+            ghost: GhostMode::Mine,
+            picker: TradePicker::new(),
+            effects: Effects::new(),
+            large_font: load_font(60),
+            font: load_font(30),
             anim_frames: 0,
             anim_frame_count: 0,
             anim_rot_vec: Vector3::new(0.0, 0.0, 0.0),
@@ -92,25 +246,143 @@ impl Draw {
 
     pub fn draw(&mut self, window: &mut Window,
                 player_states: &Vec<PlayerState>, my_id: usize, score: u32) {
-        self.board_grp.unlink();
+        let bg = self.themes[self.theme].background;
+        window.set_background_color(bg.0, bg.1, bg.2);
         self.tetromino_grp.unlink();
-        self.board_grp = window.add_group();
         self.tetromino_grp = self.board_grp.add_group();
-        self.board_grp.prepend_to_local_translation(&Vector3::new(0.0, 0.0, 31.0));
-        self.board_grp.prepend_to_local_transformation(&self.orientation);
+        if self.layout_mode == Layout::Flat {
+            self.board_grp.set_visible(false);
+            self.flat.set_visible(true);
+            self.flat.draw(window, player_states, my_id,
+                           &self.themes[self.theme], &self.opaque_mat);
+            let frame = self.flat.board_frame(player_index(player_states, my_id),
+                                              player_states.len());
+            self.draw_effects(window, frame);
+            self.draw_score(window, score);
+            return;
+        }
+        self.board_grp.set_visible(true);
+        self.flat.set_visible(false);
+        self.camera.update(player_states.iter().find(|ps| ps.id == my_id));
+        self.board_grp.set_local_transformation(self.camera.transform(&self.orientation));
 
         if self.anim_frame_count > 0 {
             self.anim_frame_count -= 1;
             self.orientation.prepend_rotation_mut(&self.anim_rot_vec);
         }
 
-        self.draw_grid(window);
         self.draw_boards(player_states, my_id);
         self.draw_tetrominos(player_states, my_id);
-        self.draw_nexts(player_states, my_id as isize);
+        self.draw_nexts(player_states, my_id);
+        let origin = (-(COLS as f32 / 2.0 - 0.5), -(ROWS as f32 / 2.0 - 0.5),
+                      -(COLS as f32 / 2.0 - 0.5));
+        let frame = (self.tetromino_grp.clone(), origin);
+        self.draw_effects(window, frame);
         self.draw_score(window, score);
     }
 
+    // renders the layered boards as they're seen now to a PNG, in software
+    // so it works whatever the GL state
+    pub fn screenshot(&self, window: &Window, player_states: &Vec<PlayerState>,
+                      my_id: usize, path: &Path) -> io::Result<()> {
+        let image = raster::render(player_states, my_id, &self.themes[self.theme],
+                                   &self.camera.transform(&self.orientation),
+                                   window.width() as usize, window.height() as usize);
+        image.save_png(path)
+    }
+
+    pub fn push_events(&mut self, events: Vec<GameEvent>) {
+        for event in events.iter() {
+            self.effects.push(event);
+        }
+    }
+
+    // flashes and particles on the board in front, then a step onward. The
+    // frame is a group rebuilt every frame and where the board's bottom left
+    // front cell sits in it.
+    fn draw_effects(&mut self, window: &mut Window, frame: (SceneNode, (f32, f32, f32))) {
+        let (mut grp, origin) = frame;
+        let centre = origin.0 + (COLS as f32 / 2.0 - 0.5);
+        for flash in self.effects.flashes.iter() {
+            let strength = flash.strength();
+            let mut slab = grp.add_cube(COLS as f32, CUBE_SIZE * strength, CUBE_SIZE + 0.1);
+            slab.prepend_to_local_translation(
+                &Vector3::new(centre, origin.1 + flash.row as f32, origin.2));
+            slab.set_color(flash.color.0, flash.color.1, flash.color.2);
+            slab.set_material(self.translucent_mat.clone());
+        }
+        for p in self.effects.particles.iter() {
+            let size = if p.bright { 0.25 } else { 0.15 };
+            let mut spark = grp.add_cube(size, size, size);
+            spark.prepend_to_local_translation(
+                &Vector3::new(origin.0 + p.pos.0, origin.1 + p.pos.1, origin.2 + p.pos.2));
+            if p.bright {
+                spark.set_color(1.0, 1.0, 0.6);
+            }
+            else {
+                spark.set_color(0.6, 0.6, 0.6);
+            }
+            spark.set_material(self.opaque_mat.clone());
+        }
+        if let Some(text) = self.effects.banner() {
+            let x = window.width() as f32 / 2.0 - 150.0;
+            window.draw_text(text, &Point2::new(x, 200.0),
+                             &self.large_font, &Point3::new(1.0, 0.8, 0.0));
+        }
+        self.effects.step();
+    }
+
+    pub fn next_theme(&mut self) {
+        self.theme = (self.theme + 1) % self.themes.len();
+        self.theme_switched = Some(Instant::now());
+        let grid = self.themes[self.theme].grid;
+        self.grid.set_color(grid.0, grid.1, grid.2);
+        self.translucent_mat = translucent(&self.shaders, &self.themes[self.theme],
+                                           &self.material);
+        self.material.set(lit(self.material.get(), &self.themes[self.theme]));
+        // lay the boards out afresh for the new colours, marks and material
+        self.layout = (Vec::new(), 0);
+    }
+
+    pub fn toggle_edges(&mut self) {
+        let mut params = self.material.get();
+        params.edges = !params.edges;
+        self.material.set(params);
+    }
+
+    fn shape_color(&self, s: Shape) -> Color {
+        self.themes[self.theme].color(cell_of_shape(s))
+    }
+
+    fn shape_marks(&self, s: Shape) -> &'static [(f32, f32)] {
+        self.themes[self.theme].pattern(cell_of_shape(s))
+    }
+
+    pub fn cycle_focus(&mut self, d: isize, ids: &Vec<usize>) {
+        if ids.is_empty() {
+            return;
+        }
+        let n = ids.len() as isize;
+        let cur = ids.iter().position(|&id| id == self.focus).unwrap_or(0) as isize;
+        self.focus = ids[((cur + d + n) % n) as usize];
+    }
+
+    pub fn toggle_layout(&mut self) {
+        self.layout_mode = match self.layout_mode {
+            Layout::Layered => Layout::Flat,
+            Layout::Flat => Layout::Layered,
+        };
+    }
+
+    // square on to our own board again, unless a rotation is playing out
+    pub fn snap_back(&mut self) {
+        if self.animating() {
+            return;
+        }
+        self.orientation = Isometry3::one();
+        self.camera.set_mode(CameraMode::Front);
+    }
+
     pub fn anim_rot(&mut self, rot_angle: f32, frames: u32) {
         self.anim_rot_vec.y = rot_angle / (frames as f32);
         self.anim_frames = frames;
@@ -118,26 +390,133 @@ impl Draw {
     }
 
     fn draw_score(&self, window: &mut Window, score: u32) {
-        let font = Font::new(&Path::new("./src/FreeSans.ttf"), 60);
         window.draw_text(&score.to_string(), &Point2::new(0.0, 80.0),
-                         &font, &Point3::new(0.0, 0.0, 1.0));
+                         &self.large_font, &Point3::new(0.0, 0.0, 1.0));
+    }
+
+    // progress toward the next board rotation, beside the score
+    pub fn draw_rotation(&self, window: &mut Window, label: &str, done: u32, goal: u32) {
+        let filled = if goal == 0 { 10 } else { (done.min(goal) * 10 / goal) as usize };
+        let bar: String = (0..10).map(|i| if i < filled { '#' } else { '-' }).collect();
+        window.draw_text(&format!("{} [{}]", label, bar), &Point2::new(200.0, 95.0),
+                         &self.font, &Point3::new(1.0, 1.0, 1.0));
+    }
+
+    // the latest error from the server, under the rotation progress
+    pub fn draw_status(&self, window: &mut Window, text: &str) {
+        window.draw_text(text, &Point2::new(200.0, 130.0), &self.font,
+                         &Point3::new(1.0, 0.5, 0.0));
     }
 
-    fn draw_nexts(&mut self, player_states: &Vec<PlayerState>, my_id: isize) {
-        let trade_id = match player_states[my_id as usize].next_tetromino.2 {
-            TradeState::NoTrade => my_id as usize,
-            TradeState::Pending(o_id) => o_id,
-            TradeState::Confirm(o_id) => o_id,
+    // a line per player along the bottom: name, score, level, lines and
+    // what they're trading
+    pub fn draw_hud(&self, window: &mut Window, player_states: &Vec<PlayerState>,
+                    my_id: usize) {
+        let mut y = window.height() - player_states.len() as f32 * 35.0 - 10.0;
+        let switched = self.theme_switched
+            .map_or(false, |at| at.elapsed() < Duration::from_millis(THEME_NAME_MS));
+        if switched {
+            window.draw_text(&format!("Theme: {}", self.themes[self.theme].name),
+                             &Point2::new(0.0, y - 35.0), &self.font,
+                             &Point3::new(0.7, 0.7, 0.7));
+        }
+        for ps in player_states.iter() {
+            let name = if ps.name.is_empty() { format!("P{}", ps.id) } else { ps.name.clone() };
+            let trading = match ps.next_tetromino.2 {
+                TradeState::Pending(t) => format!("  offering to P{}", t),
+                TradeState::Ring(ref m) => format!("  in a ring of {}", m.len()),
+                TradeState::NoTrade => String::new(),
+            };
+            let line = format!("{}  {} pts  level {}  {} lines{}",
+                               name, ps.score, ps.level(), ps.lines, trading);
+            let color = if ps.id == my_id {
+                Point3::new(1.0, 1.0, 0.0)
+            } else {
+                Point3::new(1.0, 1.0, 1.0)
+            };
+            window.draw_text(&line, &Point2::new(0.0, y), &self.font, &color);
+            y += 35.0;
+        }
+    }
+
+    pub fn draw_chat(&self, window: &mut Window, lines: &[String],
+                     entry: &Option<String>) {
+        let color = Point3::new(1.0, 1.0, 1.0);
+        for (i, line) in lines.iter().enumerate() {
+            window.draw_text(line, &Point2::new(0.0, 160.0 + i as f32 * 35.0),
+                             &self.font, &color);
+        }
+        if let &Some(ref text) = entry {
+            window.draw_text(&format!("> {}_", text),
+                             &Point2::new(0.0, 160.0 + lines.len() as f32 * 35.0),
+                             &self.font, &Point3::new(1.0, 1.0, 0.0));
+        }
+    }
+
+    pub fn draw_netstats(&self, window: &mut Window, health: &NetHealth) {
+        let mut y = 420.0;
+        if health.server_stalled() {
+            window.draw_text("connection to server stalled", &Point2::new(0.0, y),
+                             &self.font, &Point3::new(1.0, 0.0, 0.0));
+            y += 35.0;
+        }
+        for (id, report) in health.peers.iter().enumerate() {
+            if let &Some(ref r) = report {
+                let line = format!("P{}  rtt {}ms  jitter {}ms  pongs {}/{}{}",
+                                   id, r.rtt_ms, r.jitter_ms, r.pongs_received,
+                                   r.pings_sent, if r.lagging { "  LAGGING" } else { "" });
+                let color = if r.lagging {
+                    Point3::new(1.0, 0.0, 0.0)
+                } else {
+                    Point3::new(0.5, 1.0, 0.5)
+                };
+                window.draw_text(&line, &Point2::new(0.0, y), &self.font, &color);
+                y += 35.0;
+            }
+        }
+    }
+
+    fn draw_nexts(&mut self, player_states: &Vec<PlayerState>, my_id: usize) {
+        let my_idx = player_index(player_states, my_id) as isize;
+        // pieces ours is offered to get a shadow, ours does if it's offered to nobody
+        let trade_ids: Vec<usize> = match player_states[my_idx as usize].next_tetromino.2 {
+            TradeState::NoTrade => vec![my_id],
+            TradeState::Pending(o_id) => vec![o_id],
+            TradeState::Ring(ref members) =>
+                members.iter().cloned().filter(|&id| id != my_id).collect(),
         };
         
         let num_players = player_states.len() as isize;
-        let span = cmp::min(3, num_players);
-        for i in -(span / 2)..f32::ceil(span as f32 / 2.0) as isize {
-            let id = (my_id + i + num_players) % num_players;
-            let ref tetromino = player_states[id as usize].next_tetromino;
+        let (first, last) = if num_players as usize <= CAROUSEL_SLOTS {
+            self.picker.scroll_to(0.0);
+            (-(num_players / 2), f32::ceil(num_players as f32 / 2.0) as isize)
+        }
+        else {
+            // centre on whoever we're looking at, going the short way round
+            let centre = match self.picker.cursor {
+                Some(id) if self.picker.open => id,
+                _ => trade_ids[0],
+            };
+            let rel = (player_index(player_states, centre) as isize - my_idx
+                       + num_players) % num_players;
+            let scroll = self.picker.scroll;
+            let offset = [rel - num_players, rel, rel + num_players].iter().cloned()
+                .min_by_key(|&o| ((o as f32 - scroll).abs() * 100.0) as i64)
+                .unwrap();
+            self.picker.scroll_to(offset as f32);
+            let mid = self.picker.scroll.round() as isize;
+            let half = CAROUSEL_SLOTS as isize / 2;
+            (mid - half, mid + half + 1)
+        };
+        let cursor = if self.picker.open { self.picker.cursor } else { None };
+        for i in first..last {
+            let idx = ((my_idx + i) % num_players + num_players) % num_players;
+            let slot_y = (i as f32 - self.picker.scroll) * 5.0;
+            let id = player_states[idx as usize].id;
+            let ref tetromino = player_states[idx as usize].next_tetromino;
 
-            let z = ((id as i8 - my_id as i8 + num_players as i8)
-                     % num_players as i8) as f32 - (COLS as f32 / 2.0 - 0.5);
+            let z = ((idx - my_idx + num_players) % num_players) as f32
+                - (COLS as f32 / 2.0 - 0.5);
 
             for r in 0..4 {
                 for c in 0..4 {
@@ -148,20 +527,31 @@ impl Draw {
                                                         CUBE_SIZE);
                         let (mut x, y) =
                             ((c as isize - 4 as isize - (COLS / 2) as isize) as f32,
-                             r as f32 + i as f32 * 5.0);
+                             r as f32 + slot_y);
                         
-                        if let TradeState::Pending(peer_target) = tetromino.2 {
-                            if peer_target == my_id as usize && id != my_id {
-                                x -= 5.0;
-                            }
+                        if tetromino.2.offers_to(my_id) && id != my_id {
+                            x -= 5.0;
                         }
                        
                         cube.prepend_to_local_translation(&Vector3::new(x, y, z));
-                        let color = tetro_color(tetromino.0);
+                        let color = self.shape_color(tetromino.0);
                         cube.set_color(color.0, color.1, color.2);
                         cube.set_material(self.opaque_mat.clone());
+                        add_marks(&mut cube, self.shape_marks(tetromino.0), &self.opaque_mat);
+
+                        // a piece that just came to us in a trade
+                        match self.effects.pulse() {
+                            Some(swell) if id == my_id => {
+                                let size = CUBE_SIZE * (1.0 + swell * 0.6);
+                                let mut halo = self.tetromino_grp.add_cube(size, size, size);
+                                halo.prepend_to_local_translation(&Vector3::new(x, y, z));
+                                halo.set_color(1.0, 1.0, 1.0);
+                                halo.set_material(self.translucent_mat.clone());
+                            },
+                            _ => (),
+                        }
 
-                        if trade_id as isize == id {
+                        if trade_ids.contains(&id) {
                             let mut cube =
                                 self.tetromino_grp.add_cube(CUBE_SIZE,
                                                             CUBE_SIZE,
@@ -174,35 +564,189 @@ impl Draw {
                     }
                 }
             }
+
+            // count down offers we made and offers made to us
+            if tetromino.2 != TradeState::NoTrade {
+                if id == my_id || tetromino.2.offers_to(my_id) {
+                    if let Some(ref expiry) = player_states[idx as usize].trade_expiry {
+                        let mut x = (-4 - (COLS / 2) as isize) as f32;
+                        if id != my_id {
+                            x -= 5.0;
+                        }
+                        self.draw_countdown(x, slot_y - 1.0, z, expiry);
+                    }
+                }
+            }
+
+            if cursor == Some(id) {
+                let mut marker = self.tetromino_grp.add_cube(0.5, 0.5, 0.5);
+                marker.prepend_to_local_translation(
+                    &Vector3::new((-5 - (COLS / 2) as isize) as f32 - 0.5,
+                                  slot_y + 1.5, z));
+                marker.set_color(1.0, 1.0, 0.0);
+                marker.set_material(self.opaque_mat.clone());
+            }
         }
     }
 
-    fn draw_boards(&mut self, player_states: &Vec<PlayerState>, my_id: usize) {
+    // the trade picker's list of players, down the right-hand side
+    pub fn draw_picker(&self, window: &mut Window, player_states: &Vec<PlayerState>,
+                       my_id: usize) {
+        if !self.picker.open {
+            return;
+        }
+        let x = window.width() - PICKER_WIDTH;
+        let ids: Vec<usize> = player_states.iter().map(|ps| ps.id).collect();
+        let (top, rows) = self.picker.visible(my_id, &ids);
+        let others = ids.len().saturating_sub(1);
+        let title = if rows.len() < others {
+            format!("Trade with ({}-{} of {}):", top + 1, top + rows.len(), others)
+        }
+        else { "Trade with:".to_string() };
+        window.draw_text(&title, &Point2::new(x, PICKER_TOP),
+                         &self.font, &Point3::new(1.0, 1.0, 1.0));
+        let my_target = match player_states[player_index(player_states, my_id)]
+            .next_tetromino.2 {
+            TradeState::Pending(id) => Some(id),
+            _ => None,
+        };
+        let mut y = PICKER_TOP + PICKER_ROW;
+        for id in rows {
+            let ref ps = player_states[player_index(player_states, id)];
+            let mut line = format!("{} P{}  next {}",
+                                   if self.picker.cursor == Some(ps.id) { ">" } else { " " },
+                                   ps.id, shape_letter(ps.next_tetromino.0));
+            match ps.next_tetromino.2 {
+                TradeState::Pending(t) if t == my_id => line.push_str("  offering"),
+                TradeState::Ring(ref m) if m.contains(&my_id) => line.push_str("  ring"),
+                _ => (),
+            }
+            if my_target == Some(ps.id) {
+                line.push_str("  (yours)");
+            }
+            if let Some(k) = self.picker.ring.iter().position(|&id| id == ps.id) {
+                line.push_str(&format!("  ring #{}", k + 1));
+            }
+            let color = self.shape_color(ps.next_tetromino.0);
+            window.draw_text(&line, &Point2::new(x, y), &self.font,
+                             &Point3::new(color.0, color.1, color.2));
+            y += PICKER_ROW;
+        }
+    }
 
-        let num_players = player_states.len();
+    // a row of ticks for the seconds left and one below for the drops left
+    fn draw_countdown(&mut self, x: f32, y: f32, z: f32, expiry: &TradeExpiry) {
+        let rows = [(f32::ceil(expiry.secs_left) as u32, 0.0, (1.0, 1.0, 1.0)),
+                    (expiry.drops_left, -0.5, (1.0, 0.5, 0.0))];
+        for &(count, dy, color) in rows.iter() {
+            for t in 0..count {
+                let mut tick = self.tetromino_grp.add_cube(0.3, 0.3, 0.3);
+                tick.prepend_to_local_translation(
+                    &Vector3::new(x + t as f32 * 0.4, y + dy, z));
+                tick.set_color(color.0, color.1, color.2);
+                tick.set_material(self.opaque_mat.clone());
+            }
+        }
+    }
 
-        for ps in player_states {
+    fn draw_boards(&mut self, player_states: &Vec<PlayerState>, my_id: usize) {
+        let ids: Vec<usize> = player_states.iter().map(|ps| ps.id).collect();
+        if self.layout != (ids.clone(), my_id) {
+            self.layout_boards(player_states.len(), player_index(player_states, my_id));
+            self.layout = (ids, my_id);
+        }
+
+        let ref theme = self.themes[self.theme];
+        for (ps, nodes) in player_states.iter().zip(self.boards.iter_mut()) {
+            let mat = if ps.id == my_id { &self.opaque_mat } else { &self.translucent_mat };
+            if nodes.last.as_ref() == Some(&ps.board) {
+                continue;
+            }
             for r in 0..ROWS - 2 {
                 for c in 0..COLS {
-                    if ps.board[r][c] != Cell::E {
-                        let mut cube =
-                            self.board_grp.add_cube(CUBE_SIZE, CUBE_SIZE, CUBE_SIZE);
-                        cube.prepend_to_local_translation(
-                            &Vector3::new(c as f32 - (COLS as f32 / 2.0 - 0.5),
-                                          r as f32 - (ROWS as f32 / 2.0 - 0.5),
-                                          -(COLS as f32 / 2.0 - 0.5) +
-                                          ((ps.id as i8 - my_id as i8
-                                            + num_players as i8)
-                                           % num_players as i8) as f32));
-                        let color = cell_color(ps.board[r][c]);
+                    let changed = match nodes.last {
+                        Some(ref last) => last[r][c] != ps.board[r][c],
+                        None => true,
+                    };
+                    if !changed {
+                        continue;
+                    }
+                    let ref mut cube = nodes.cells[r * COLS + c];
+                    if let Some(mut old) = nodes.marks[r * COLS + c].take() {
+                        old.unlink();
+                    }
+                    if ps.board[r][c] == Cell::E {
+                        cube.set_visible(false);
+                    }
+                    else {
+                        let color = theme.color(ps.board[r][c]);
                         cube.set_color(color.0, color.1, color.2);
-                        if ps.id == my_id {
-                            cube.set_material(self.opaque_mat.clone());
-                        }
-                        else {
-                            cube.set_material(self.translucent_mat.clone());
-                        }
+                        cube.set_visible(true);
+                        nodes.marks[r * COLS + c] =
+                            add_marks(cube, theme.pattern(ps.board[r][c]), mat);
+                    }
+                }
+            }
+            nodes.last = Some(ps.board);
+        }
+    }
+
+    // a hidden cube for every cell of every board, in front-to-back order
+    // starting with the viewer's
+    fn layout_boards(&mut self, num_players: usize, my_idx: usize) {
+        self.cells_grp.unlink();
+        self.cells_grp = self.board_grp.add_group();
+        self.boards.clear();
+
+        for idx in 0..num_players {
+            let depth = ((idx + num_players - my_idx) % num_players) as f32;
+            let mut cells = Vec::with_capacity((ROWS - 2) * COLS);
+            for r in 0..ROWS - 2 {
+                for c in 0..COLS {
+                    let mut cube = self.cells_grp.add_cube(CUBE_SIZE, CUBE_SIZE, CUBE_SIZE);
+                    cube.prepend_to_local_translation(
+                        &Vector3::new(c as f32 - (COLS as f32 / 2.0 - 0.5),
+                                      r as f32 - (ROWS as f32 / 2.0 - 0.5),
+                                      -(COLS as f32 / 2.0 - 0.5) + depth));
+                    if idx == my_idx {
+                        cube.set_material(self.opaque_mat.clone());
+                    }
+                    else {
+                        cube.set_material(self.translucent_mat.clone());
                     }
+                    cube.set_visible(false);
+                    cells.push(cube);
+                }
+            }
+            self.boards.push(BoardNodes {
+                marks: vec![None; cells.len()],
+                cells: cells,
+                last: None,
+            });
+        }
+    }
+
+    // a translucent copy of the falling piece where it would land
+    fn draw_ghost(&mut self, ps: &PlayerState, depth: f32) {
+        let landing = ps.landing_pos();
+        if landing == ps.tetro_pos {
+            return;
+        }
+        let ref tetromino = ps.tetromino;
+        let color = self.shape_color(tetromino.0);
+        for r in 0..4 {
+            for c in 0..4 {
+                if tetromino.0[tetromino.1][r][c] != 0 {
+                    let mut cube = self.tetromino_grp.add_cube(
+                        CUBE_SIZE, CUBE_SIZE, CUBE_SIZE);
+                    cube.prepend_to_local_translation(
+                        &Vector3::new((landing.1 + c as i8) as f32 -
+                                      (COLS as f32 / 2.0 - 0.5),
+                                      (landing.0 + r as i8) as f32 -
+                                      (ROWS as f32 / 2.0 - 0.5),
+                                      -(COLS as f32 / 2.0 - 0.5) + depth));
+                    cube.set_color(color.0 * 0.5, color.1 * 0.5, color.2 * 0.5);
+                    cube.set_material(self.translucent_mat.clone());
                 }
             }
         }
@@ -211,7 +755,18 @@ impl Draw {
     fn draw_tetrominos(&mut self, player_states: &Vec<PlayerState>, my_id: usize) {
 
         let num_players = player_states.len();
-        for ps in player_states {
+        let my_idx = player_index(player_states, my_id);
+
+        for (idx, ps) in player_states.iter().enumerate() {
+            let depth = ((idx + num_players - my_idx) % num_players) as f32;
+            let ghost = match self.ghost {
+                GhostMode::Off => false,
+                GhostMode::Mine => ps.id == my_id,
+                GhostMode::Everyone => true,
+            };
+            if ghost && !ps.paused {
+                self.draw_ghost(ps, depth);
+            }
             for r in 0..4 {
                 for c in 0..4 {
                     let ref tetromino = ps.tetromino;
@@ -224,54 +779,20 @@ impl Draw {
                                           (COLS as f32 / 2.0 - 0.5),
                                           (ps.tetro_pos.0 + r as i8) as f32 -
                                           (ROWS as f32 / 2.0 - 0.5),
-                                          -(COLS as f32 / 2.0 - 0.5) +
-                                          ((ps.id as i8 - my_id as i8
-                                            + num_players as i8)
-                                           % num_players as i8) as f32));
-                        let color = tetro_color(tetromino.0);
+                                          -(COLS as f32 / 2.0 - 0.5) + depth));
+                        let color = self.shape_color(tetromino.0);
                         cube.set_color(color.0, color.1, color.2);
-                        if ps.id == my_id {
-                            cube.set_material(self.opaque_mat.clone());
+                        let mat = if ps.id == my_id {
+                            self.opaque_mat.clone()
                         }
                         else {
-                            cube.set_material(self.translucent_mat.clone());
-                        }
+                            self.translucent_mat.clone()
+                        };
+                        add_marks(&mut cube, self.shape_marks(tetromino.0), &mat);
+                        cube.set_material(mat);
                     }
                 }
             }
         }
     }
-
-    fn draw_grid(&self, window: &mut Window) {
-        let wt = self.board_grp.data().world_transformation();
-        for x in -(COLS as isize / 2)..(COLS as isize / 2 + 1) {
-            for z in -(COLS as isize / 2)..(COLS as isize / 2 + 1) {
-                let p1 = Point3::new(x as f32, -(ROWS as f32 / 2.0), z as f32);
-                let _p1 = wt * p1;
-                let p2 = Point3::new(x as f32, (ROWS as f32 / 2.0) - 2.0, z as f32);
-                let _p2 = wt * p2;
-                let c = Point3::new(0.5 as f32, 0.5 as f32, 0.5 as f32);
-                window.draw_line(&_p1, &_p2, &c);
-            }
-        }
-        
-        for y in -(ROWS as isize / 2)..((ROWS - 2) as isize / 2) {
-            for x in -(COLS as isize / 2)..(COLS as isize / 2 + 1) {
-                let p1 = Point3::new(x as f32, y as f32, -(COLS as f32 / 2.0));
-                let _p1 = wt * p1;
-                let p2 = Point3::new(x as f32, y as f32, COLS as f32 / 2.0);
-                let _p2 = wt * p2;
-                let c = Point3::new(0.5 as f32, 0.5 as f32, 0.5 as f32);
-                window.draw_line(&_p1, &_p2, &c);
-            }
-            for z in -(COLS as isize / 2)..(COLS as isize / 2 + 1) {
-                let p1 = Point3::new(COLS as f32 / 2.0, y as f32, z as f32);
-                let _p1 = wt * p1;
-                let p2 = Point3::new(-(COLS as f32 / 2.0), y as f32, z as f32);
-                let _p2 = wt * p2;
-                let c = Point3::new(0.5 as f32, 0.5 as f32, 0.5 as f32);
-                window.draw_line(&_p1, &_p2, &c);
-            }
-        }
-    }
 }
@@ -0,0 +1,165 @@
+use std::f32::consts::PI;
+
+use rand::{thread_rng, Rng};
+
+use events::GameEvent;
+use playerstate::{ROWS, COLS};
+
+// how long each kind of effect lasts, in frames
+const FLASH_FRAMES: u32 = 20;
+const ROTATE_FLASH_FRAMES: u32 = 10;
+const BURST_FRAMES: u32 = 40;
+const DUST_FRAMES: u32 = 15;
+const PULSE_FRAMES: u32 = 45;
+const BANNER_FRAMES: u32 = 90;
+
+const GRAVITY: f32 = 0.02;
+
+// a row lit up, as it clears or fills
+pub struct Flash {
+    pub row: usize,
+    pub frames: u32,
+    pub length: u32,
+    pub color: (f32, f32, f32),
+}
+
+impl Flash {
+    // 1 when it starts, fading to 0
+    pub fn strength(&self) -> f32 {
+        self.frames as f32 / self.length as f32
+    }
+}
+
+// in board cells, the same units the cubes are laid out in
+pub struct Particle {
+    pub pos: (f32, f32, f32),
+    vel: (f32, f32, f32),
+    pub frames: u32,
+    pub bright: bool,
+}
+
+// animations started by game events, stepped once per drawn frame. All of
+// them play on our own board, in whichever layout it's drawn.
+pub struct Effects {
+    pub flashes: Vec<Flash>,
+    pub particles: Vec<Particle>,
+    pulse: u32,
+    banner: Option<(String, u32)>,
+}
+
+impl Effects {
+    pub fn new() -> Effects {
+        Effects {
+            flashes: Vec::new(),
+            particles: Vec::new(),
+            pulse: 0,
+            banner: None,
+        }
+    }
+
+    pub fn push(&mut self, event: &GameEvent) {
+        match *event {
+            GameEvent::PieceLocked(ref cells) => {
+                for &(r, c) in cells.iter() {
+                    self.burst(r, c, 2, 0.05, DUST_FRAMES, false);
+                }
+            },
+            GameEvent::LinesCleared { ref rows } => {
+                for &row in rows.iter() {
+                    self.flashes.push(Flash {
+                        row: row,
+                        frames: FLASH_FRAMES,
+                        length: FLASH_FRAMES,
+                        color: (1.0, 1.0, 1.0),
+                    });
+                    for c in 0..COLS {
+                        self.burst(row, c, 3, 0.2, BURST_FRAMES, true);
+                    }
+                }
+                if rows.len() == 4 {
+                    self.banner = Some(("TETRIS".to_string(), BANNER_FRAMES));
+                }
+            },
+            GameEvent::TSpin { lines } => {
+                let text = match lines {
+                    0 => "T-SPIN",
+                    1 => "T-SPIN SINGLE",
+                    2 => "T-SPIN DOUBLE",
+                    _ => "T-SPIN TRIPLE",
+                };
+                self.banner = Some((text.to_string(), BANNER_FRAMES));
+            },
+            GameEvent::TradeCompleted(_) => self.pulse = PULSE_FRAMES,
+            GameEvent::BoardRotated => {
+                // a quick sweep up the whole board
+                for row in 0..ROWS - 2 {
+                    self.flashes.push(Flash {
+                        row: row,
+                        frames: ROTATE_FLASH_FRAMES + row as u32 / 2,
+                        length: ROTATE_FLASH_FRAMES * 2,
+                        color: (1.0, 1.0, 1.0),
+                    });
+                }
+            },
+            GameEvent::GarbageReceived { ref rows } => {
+                // red, and outlasting the sweep
+                for &row in rows.iter() {
+                    self.flashes.push(Flash {
+                        row: row,
+                        frames: FLASH_FRAMES * 2,
+                        length: FLASH_FRAMES * 2,
+                        color: (1.0, 0.25, 0.2),
+                    });
+                }
+            },
+        }
+    }
+
+    fn burst(&mut self, row: usize, col: usize, count: usize, speed: f32,
+             frames: u32, bright: bool) {
+        let mut rng = thread_rng();
+        for _ in 0..count {
+            self.particles.push(Particle {
+                pos: (col as f32, row as f32, 0.0),
+                vel: (rng.gen_range(-speed, speed),
+                      rng.gen_range(0.0, speed * 1.5),
+                      rng.gen_range(-speed, speed)),
+                frames: frames - rng.gen_range(0, frames / 3 + 1),
+                bright: bright,
+            });
+        }
+    }
+
+    pub fn step(&mut self) {
+        for f in self.flashes.iter_mut() {
+            f.frames -= 1;
+        }
+        self.flashes.retain(|f| f.frames > 0);
+        for p in self.particles.iter_mut() {
+            p.pos.0 += p.vel.0;
+            p.pos.1 += p.vel.1;
+            p.pos.2 += p.vel.2;
+            p.vel.1 -= GRAVITY;
+            p.frames -= 1;
+        }
+        self.particles.retain(|p| p.frames > 0);
+        self.pulse = self.pulse.saturating_sub(1);
+        self.banner = match self.banner.take() {
+            Some((text, frames)) if frames > 1 => Some((text, frames - 1)),
+            _ => None,
+        };
+    }
+
+    // how swollen the traded piece is drawn, beating three times as it fades
+    pub fn pulse(&self) -> Option<f32> {
+        if self.pulse == 0 {
+            return None;
+        }
+        let t = self.pulse as f32 / PULSE_FRAMES as f32;
+        Some(t * (t * 3.0 * PI).sin().abs())
+    }
+
+    pub fn banner(&self) -> Option<&str> {
+        self.banner.as_ref().map(|&(ref text, _)| &text[..])
+    }
+}
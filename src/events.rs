@@ -0,0 +1,19 @@
+use playerstate::Shape;
+
+// things the engine reports as they happen, for whatever wants to show them
+// off. They stay on the client that made them and are never sent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GameEvent {
+    // the falling piece came to rest on these (row, col) cells
+    PieceLocked(Vec<(usize, usize)>),
+    // full rows, numbered as they were before clearing
+    LinesCleared { rows: Vec<usize> },
+    // a T rotated into a slot with three corners filled, then locked
+    TSpin { lines: u32 },
+    // a traded shape arrived in place of one of ours
+    TradeCompleted(Shape),
+    // our board came back from a rotation
+    BoardRotated,
+    // the rotation left our stack taller, these rows are newly in it
+    GarbageReceived { rows: Vec<usize> },
+}
@@ -0,0 +1,237 @@
+use std::f32::consts::PI;
+
+use kiss3d::window::Window;
+use kiss3d::scene::SceneNode;
+
+use nalgebra::{Vector3, Isometry3};
+
+use num::traits::One;
+
+use playerstate::{PlayerState, Shape, Cell, ROWS, COLS, TradeState, cell_of_shape};
+use theme::Theme;
+use draw::{Material, CUBE_SIZE, add_marks};
+
+// board width plus the gap to the next board, in cells
+const PITCH: f32 = COLS as f32 + 3.0;
+// room above the boards for the next pieces and trade bridges
+const HEADROOM: f32 = 7.0;
+// the field of view of the window's default camera
+const FOV: f32 = PI / 4.0;
+
+// every board side by side and square on, the same cubes as the layered view
+// but all at one depth so nobody's board hides behind anyone else's
+pub struct FlatView {
+    grp: SceneNode,
+    // rebuilt every frame like the layered view's pieces
+    pieces_grp: SceneNode,
+    cells_grp: SceneNode,
+    boards: Vec<FlatBoard>,
+    // player ids, viewer and theme the cells were laid out for
+    layout: (Vec<usize>, usize, String),
+}
+
+struct FlatBoard {
+    cells: Vec<SceneNode>,
+    marks: Vec<Option<SceneNode>>,
+    last: Option<[[Cell; COLS]; ROWS]>,
+}
+
+// left to right in id order, whichever way the camera's x axis runs
+fn board_x(idx: usize, num_players: usize) -> f32 {
+    -(idx as f32 - (num_players as f32 - 1.0) / 2.0) * PITCH
+}
+
+fn cell_pos(x0: f32, r: f32, c: f32) -> Vector3<f32> {
+    Vector3::new(x0 + c - (COLS as f32 / 2.0 - 0.5), r - (ROWS as f32 / 2.0 - 0.5), 0.0)
+}
+
+impl FlatView {
+    pub fn new(window: &mut Window) -> FlatView {
+        let mut grp = window.add_group();
+        let pieces_grp = grp.add_group();
+        let cells_grp = grp.add_group();
+        grp.set_visible(false);
+        FlatView {
+            grp: grp,
+            pieces_grp: pieces_grp,
+            cells_grp: cells_grp,
+            boards: Vec::new(),
+            layout: (Vec::new(), 0, String::new()),
+        }
+    }
+
+    pub fn set_visible(&mut self, visible: bool) {
+        self.grp.set_visible(visible);
+    }
+
+    pub fn draw(&mut self, window: &mut Window, player_states: &Vec<PlayerState>,
+                my_id: usize, theme: &Theme, mat: &Material) {
+        self.pieces_grp.unlink();
+        self.pieces_grp = self.grp.add_group();
+
+        // far enough back to fit every board across and one board high
+        let n = player_states.len();
+        let aspect = window.width() as f32 / window.height().max(1.0) as f32;
+        let span = 2.0 * (FOV / 2.0).tan();
+        let distance = ((ROWS as f32 + HEADROOM) / span)
+            .max(n as f32 * PITCH / (span * aspect));
+        self.grp.set_local_transformation(Isometry3::one());
+        self.grp.prepend_to_local_translation(
+            &Vector3::new(0.0, -HEADROOM / 2.0, distance + 1.0));
+
+        self.draw_boards(player_states, my_id, theme, mat);
+        for (idx, ps) in player_states.iter().enumerate() {
+            let x0 = board_x(idx, n);
+            self.draw_piece(ps.tetromino.0, ps.tetromino.1, x0,
+                            ps.tetro_pos.0 as f32, ps.tetro_pos.1 as f32, theme, mat);
+            self.draw_piece(ps.next_tetromino.0, ps.next_tetromino.1, x0,
+                            (ROWS - 1) as f32, (COLS / 2 - 2) as f32, theme, mat);
+            self.draw_trade(ps, player_states, my_id, mat);
+        }
+    }
+
+    // the group drawn afresh each frame and where the given board's bottom
+    // left cell sits in it, for effects to be drawn over that board
+    pub fn board_frame(&self, idx: usize, num_players: usize) -> (SceneNode, (f32, f32, f32)) {
+        let origin = cell_pos(board_x(idx, num_players), 0.0, 0.0);
+        (self.pieces_grp.clone(), (origin.x, origin.y, origin.z))
+    }
+
+    fn draw_boards(&mut self, player_states: &Vec<PlayerState>, my_id: usize,
+                   theme: &Theme, mat: &Material) {
+        let ids = player_states.iter().map(|ps| ps.id).collect();
+        let layout = (ids, my_id, theme.name.clone());
+        if self.layout != layout {
+            self.layout_boards(player_states, my_id, theme, mat);
+            self.layout = layout;
+        }
+
+        for (ps, nodes) in player_states.iter().zip(self.boards.iter_mut()) {
+            if nodes.last.as_ref() == Some(&ps.board) {
+                continue;
+            }
+            for r in 0..ROWS - 2 {
+                for c in 0..COLS {
+                    let cell = ps.board[r][c];
+                    let changed = match nodes.last {
+                        Some(ref last) => last[r][c] != cell,
+                        None => true,
+                    };
+                    if !changed {
+                        continue;
+                    }
+                    let ref mut cube = nodes.cells[r * COLS + c];
+                    if let Some(mut old) = nodes.marks[r * COLS + c].take() {
+                        old.unlink();
+                    }
+                    if cell == Cell::E {
+                        cube.set_visible(false);
+                    }
+                    else {
+                        let color = theme.color(cell);
+                        cube.set_color(color.0, color.1, color.2);
+                        cube.set_visible(true);
+                        nodes.marks[r * COLS + c] = add_marks(cube, theme.pattern(cell), mat);
+                    }
+                }
+            }
+            nodes.last = Some(ps.board);
+        }
+    }
+
+    // hidden cubes for every cell, over a backing panel per board that's
+    // lighter for the viewer's
+    fn layout_boards(&mut self, player_states: &Vec<PlayerState>, my_id: usize,
+                     theme: &Theme, mat: &Material) {
+        self.cells_grp.unlink();
+        self.cells_grp = self.grp.add_group();
+        self.boards.clear();
+
+        let n = player_states.len();
+        for (idx, ps) in player_states.iter().enumerate() {
+            let x0 = board_x(idx, n);
+            let mut panel = self.cells_grp.add_cube(COLS as f32, (ROWS - 2) as f32, 0.1);
+            let mut centre = cell_pos(x0, (ROWS - 3) as f32 / 2.0, (COLS - 1) as f32 / 2.0);
+            centre.z = CUBE_SIZE;
+            panel.prepend_to_local_translation(&centre);
+            let shade = if ps.id == my_id { 0.35 } else { 0.2 };
+            panel.set_color(theme.grid.0 * shade, theme.grid.1 * shade, theme.grid.2 * shade);
+            panel.set_material(mat.clone());
+
+            let mut cells = Vec::with_capacity((ROWS - 2) * COLS);
+            for r in 0..ROWS - 2 {
+                for c in 0..COLS {
+                    let mut cube = self.cells_grp.add_cube(CUBE_SIZE, CUBE_SIZE, CUBE_SIZE);
+                    cube.prepend_to_local_translation(&cell_pos(x0, r as f32, c as f32));
+                    cube.set_material(mat.clone());
+                    cube.set_visible(false);
+                    cells.push(cube);
+                }
+            }
+            self.boards.push(FlatBoard {
+                marks: vec![None; cells.len()],
+                cells: cells,
+                last: None,
+            });
+        }
+    }
+
+    fn draw_piece(&mut self, shape: Shape, rot: usize, x0: f32, row: f32, col: f32,
+                  theme: &Theme, mat: &Material) {
+        let cell = cell_of_shape(shape);
+        let color = theme.color(cell);
+        for r in 0..4 {
+            for c in 0..4 {
+                if shape[rot][r][c] != 0 {
+                    let mut cube = self.pieces_grp.add_cube(CUBE_SIZE, CUBE_SIZE, CUBE_SIZE);
+                    cube.prepend_to_local_translation(
+                        &cell_pos(x0, row + r as f32, col + c as f32));
+                    cube.set_color(color.0, color.1, color.2);
+                    cube.set_material(mat.clone());
+                    add_marks(&mut cube, theme.pattern(cell), mat);
+                }
+            }
+        }
+    }
+
+    // a bridge of dots from a player's next piece to each player it's
+    // offered to: white for an offer, orange for a ring, and brighter for
+    // anything involving the viewer
+    fn draw_trade(&mut self, ps: &PlayerState, player_states: &Vec<PlayerState>,
+                  my_id: usize, mat: &Material) {
+        let (targets, color) = match ps.next_tetromino.2 {
+            TradeState::NoTrade => return,
+            TradeState::Pending(t) => (vec![t], (0.8, 0.8, 0.8)),
+            TradeState::Ring(ref members) => {
+                // each member passes to the next, so draw only our leg
+                let pos = members.iter().position(|&id| id == ps.id);
+                let next = pos.map(|p| members[(p + 1) % members.len()]);
+                (next.into_iter().collect(), (0.8, 0.5, 0.0))
+            },
+        };
+        let n = player_states.len();
+        let from = player_states.iter().position(|s| s.id == ps.id).unwrap_or(0);
+        let y = (ROWS + 3) as f32 - (ROWS as f32 / 2.0 - 0.5);
+        for target in targets {
+            let to = match player_states.iter().position(|s| s.id == target) {
+                Some(to) => to,
+                None => continue,
+            };
+            let bright = if ps.id == my_id || target == my_id { 1.25 } else { 1.0 };
+            let (a, b) = (board_x(from, n), board_x(to, n));
+            let steps = ((b - a).abs() * 2.0) as usize;
+            for k in 0..steps + 1 {
+                let x = a + (b - a) * k as f32 / steps.max(1) as f32;
+                let mut dot = self.pieces_grp.add_cube(0.2, 0.2, 0.2);
+                dot.prepend_to_local_translation(&Vector3::new(x, y, 0.0));
+                dot.set_color(color.0 * bright, color.1 * bright, color.2 * bright);
+                dot.set_material(mat.clone());
+            }
+            // the arrowhead, over the receiving board
+            let mut head = self.pieces_grp.add_cube(0.5, 0.5, 0.5);
+            head.prepend_to_local_translation(&Vector3::new(b, y - 0.5, 0.0));
+            head.set_color(color.0 * bright, color.1 * bright, color.2 * bright);
+            head.set_material(mat.clone());
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use std::time::{SystemTime, Instant, Duration};
+use std::path::Path;
+
+use playerstate::{PlayerState, BoardState};
+use multiplayer::{Mp, Role};
+use protocol::ServerMessage;
+use chat::ChatLog;
+use netstats::NetHealth;
+use replay::Replay;
+use tradelog::TradeLog;
+use rotation::RotationClock;
+use trade::TradeSession;
+
+const REPLAY_PATH: &'static str = "last_game.t3r";
+// how long a status message stays up
+const STATUS_SECS: u64 = 5;
+
+// the client's side of a game, whatever front-end is drawing it. The
+// front-end polls, updates, steps and draws in that order every frame.
+pub struct Game {
+    pub my_id: Option<usize>,
+    pub my_state: PlayerState,
+    // indexed by player id, vacated slots are None
+    peer_states: Vec<Option<PlayerState>>,
+    pub trade: TradeSession,
+    pub trade_log: TradeLog,
+    pub rot_clock: RotationClock,
+    pub chat_log: ChatLog,
+    pub net_health: NetHealth,
+    // the last error from the server or the link to it, and when it came
+    status: Option<(String, Instant)>,
+    tick: u32,
+    t1: SystemTime,
+    replay: Replay,
+}
+
+impl Game {
+    pub fn new(mp: &Mp) -> Game {
+        let my_id = match mp.role {
+            Role::Player(id) => Some(id),
+            Role::Spectator => None,
+        };
+        let mut my_state = PlayerState::new(my_id.unwrap_or(0));
+        my_state.name = mp.name.clone();
+        if my_id.is_some() {
+            my_state.begin();
+        }
+        Game {
+            my_id: my_id,
+            my_state: my_state,
+            peer_states: vec![None; my_id.map_or(0, |id| id + 1)],
+            trade: TradeSession::new(mp.settings.trade),
+            trade_log: TradeLog::new(),
+            rot_clock: RotationClock::new(mp.settings.rotation),
+            chat_log: ChatLog::new(),
+            net_health: NetHealth::new(),
+            status: None,
+            tick: 0,
+            t1: SystemTime::now(),
+            replay: Replay::new(mp.room),
+        }
+    }
+
+    // handles everything the server sent since last time, true if that
+    // started a board rotation
+    pub fn poll(&mut self, mp: &mut Mp) -> bool {
+        let mut rotating = false;
+        let playing = self.my_id.is_some();
+        for msg in mp.poll() {
+            self.net_health.last_heard = Instant::now();
+            match msg {
+                ServerMessage::State(ps) => {
+                    let id = ps.id;
+                    while self.peer_states.len() <= id {
+                        self.peer_states.push(None);
+                    }
+                    self.peer_states[id] = Some(ps);
+                },
+                ServerMessage::PlayerLeft(id) => {
+                    if id < self.peer_states.len() {
+                        self.peer_states[id] = None;
+                    }
+                    if id < self.net_health.peers.len() {
+                        self.net_health.peers[id] = None;
+                    }
+                },
+                ServerMessage::Ping(seq) =>
+                    mp.pong(seq),
+                ServerMessage::PeerStatus(id, report) =>
+                    self.net_health.update(id, report),
+                ServerMessage::Chat(from, text) =>
+                    self.chat_log.push(from, &text),
+                ServerMessage::TradeCommitted(commit) => {
+                    self.trade_log.committed(self.tick, &commit);
+                    if playing {
+                        self.trade.committed(&mut self.my_state, &commit);
+                        mp.issue_update(self.my_state.clone());
+                    }
+                },
+                ServerMessage::TradeClosed(to, why) => {
+                    if playing {
+                        self.trade.closed(&mut self.my_state, to, why);
+                        mp.issue_update(self.my_state.clone());
+                    }
+                },
+                ServerMessage::RingProposed(id, members) => {
+                    if playing {
+                        self.trade.ring_proposed(&self.my_state, id, members);
+                    }
+                },
+                ServerMessage::RingCommitted(commit) => {
+                    self.trade_log.ring_committed(self.tick, &commit);
+                    if playing {
+                        self.trade.ring_committed(&mut self.my_state, &commit);
+                        mp.issue_update(self.my_state.clone());
+                    }
+                },
+                ServerMessage::RingClosed(id, why) => {
+                    if playing {
+                        self.trade.ring_closed(&mut self.my_state, id, why);
+                        mp.issue_update(self.my_state.clone());
+                    }
+                },
+                ServerMessage::Rotate(snapshot) => {
+                    // every client swaps boards on this message, so nobody
+                    // plays on a board another has already handed over. Any
+                    // animation only shows what has happened.
+                    for ps in snapshot {
+                        if Some(ps.id) == self.my_id {
+                            self.my_state.rotated_to(ps.board);
+                            self.my_state.board_state = BoardState::Stable;
+                            self.my_state.rotate_vote = false;
+                            self.my_state.new_tetromino();
+                            self.my_state.paused = false;
+                            mp.issue_update(self.my_state.clone());
+                        }
+                        else if let Some(&mut Some(ref mut peer)) = self.peer_states.get_mut(ps.id) {
+                            // until their own state comes, so we don't
+                            // follow them into another rotation
+                            peer.board = ps.board;
+                            peer.board_state = BoardState::Stable;
+                        }
+                    }
+                    rotating = true;
+                },
+                ServerMessage::Error(e) =>
+                    self.status = Some((e, Instant::now())),
+                _ => (),
+            }
+        }
+        rotating
+    }
+
+    // for the front-end to show in place of printing, which would land in
+    // the middle of a raw-mode terminal
+    pub fn status(&self) -> Option<&str> {
+        match self.status {
+            Some((ref text, at)) if at.elapsed() < Duration::from_secs(STATUS_SECS) =>
+                Some(&text[..]),
+            _ => None,
+        }
+    }
+
+    // every present player's state in id order, ours included
+    pub fn states(&self) -> Vec<PlayerState> {
+        let mut states = Vec::new();
+        for i in 0..self.peer_states.len() {
+            if Some(i) != self.my_id {
+                if let Some(ref ps) = self.peer_states[i] {
+                    states.push(ps.clone());
+                }
+            }
+            else { states.push(self.my_state.clone()); }
+        }
+        states
+    }
+
+    // moves trades and rotations along, returning the total score
+    pub fn update(&mut self, mp: &mut Mp, states: &Vec<PlayerState>) -> u32 {
+        self.trade_log.update(states);
+        // peers' scores are theirs to report, so don't let one overflow the sum
+        let score = states.iter().fold(0u32, |acc, x| acc.saturating_add(x.score));
+
+        // spectators own no state, so they neither vote on rotations nor trade
+        match self.my_id {
+            Some(_) => {
+                self.trade.update(&mut self.my_state, states);
+                for msg in self.trade.drain_outbox() {
+                    mp.send_trade(msg);
+                }
+                for notice in self.trade.drain_notices() {
+                    self.chat_log.notice(&notice);
+                }
+                self.check_rot(states);
+            },
+            None => {
+                self.rot_clock.update(states);
+                if states.iter().any(|s| s.board_state == BoardState::Ready) {
+                    self.rot_clock.restart(states);
+                }
+            },
+        }
+        score
+    }
+
+    fn check_rot(&mut self, cur_states: &Vec<PlayerState>) {
+        let ref mut my_state = self.my_state;
+        self.rot_clock.update(cur_states);
+        match my_state.board_state.clone() {
+            BoardState::Stable => {
+                // follow anyone who got there first, so nobody is left waiting
+                // on a trigger they see a frame late
+                let leader = cur_states.iter().any(|&ref x| x.board_state == BoardState::Ready);
+                if self.rot_clock.due(cur_states) || leader {
+                    self.rot_clock.restart(cur_states);
+                    my_state.paused = true;
+                    my_state.board_state = BoardState::Ready;
+                }
+            },
+            // the server orders the rotation once every player is ready, and
+            // poll applies it
+            BoardState::Ready | BoardState::Confirm => (),
+        }
+    }
+
+    // the game clock: pieces fall a row and the replay takes a frame every
+    // half second
+    pub fn step(&mut self, mp: &mut Mp, states: &Vec<PlayerState>) {
+        if let Ok(d) = SystemTime::now().duration_since(self.t1) {
+            if d.as_secs() > 0.5 as u64 {
+                self.t1 = SystemTime::now();
+                self.tick += 1;
+                self.replay.record(self.tick, states);
+                if self.my_id.is_some() {
+                    self.my_state.move_down();
+                    mp.issue_update(self.my_state.clone());
+                }
+            }
+        }
+    }
+
+    pub fn finish(mut self, mp: &mut Mp) {
+        mp.leave_room();
+        for line in self.trade_log.summary() {
+            println!("{}", line);
+        }
+        self.replay.trades = self.trade_log.records.clone();
+        if let Err(e) = self.replay.save(Path::new(REPLAY_PATH)) {
+            println!("Couldn't save replay: {:?}", e);
+        }
+    }
+}
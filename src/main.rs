@@ -3,252 +3,348 @@ extern crate nalgebra;
 extern crate glfw;
 extern crate rand;
 extern crate num;
-extern crate rustc_serialize;
-extern crate bincode;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 extern crate gl;
+extern crate mio;
 
 mod playerstate;
 mod draw;
 mod multiplayer;
 mod networkadapter;
 mod other_material;
+mod protocol;
+mod room;
+mod chat;
+mod netstats;
+mod schema;
+mod replay;
+mod trade;
+mod picker;
+mod tradelog;
+mod rotation;
+mod boardmap;
+mod events;
+mod effects;
+mod theme;
+mod camera;
+mod flat;
+mod game;
+mod tui;
+mod png;
+mod raster;
 
-use playerstate::{PlayerState, TradeState, BoardState, Shape};
+use playerstate::PlayerState;
 use draw::Draw;
 use multiplayer::Mp;
-use networkadapter::*;
+use chat::ChatEntry;
+use trade::{TradeSession, RING_MIN};
+use theme::Theme;
+use schema::SchemaError;
+use game::Game;
+use replay::Replay;
+use camera::CameraRig;
 
 use kiss3d::window::Window;
 use kiss3d::light::Light;
 
-use nalgebra::{Vector3, Rotation};
+use nalgebra::{Vector3, Isometry3, Rotation};
+
+use num::traits::One;
 
 use glfw::{Action, WindowEvent, Key};
 
-use std::time::{SystemTime};
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::env;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const THEME_PATH: &'static str = "theme.t3theme";
+const ROTATION_FRAMES: u32 = 30;
+// size of images rendered without a window
+const RENDER_SIZE: (usize, usize) = (800, 600);
 
 fn main() {
 
+    let args: Vec<String> = env::args().collect();
+    let text_only = args.iter().any(|arg| arg == "--tui");
+
+    let mut themes = Theme::builtins();
+    match Theme::load(Path::new(THEME_PATH)) {
+        Ok(theme) => themes.insert(0, theme),
+        // no theme file, the built-in ones will do
+        Err(SchemaError::Io(_)) => (),
+        Err(e) => println!("Couldn't load theme: {:?}", e),
+    }
+
+    if let Some(i) = args.iter().position(|arg| arg == "--render") {
+        match (args.get(i + 1), args.get(i + 2)) {
+            (Some(replay), Some(out)) =>
+                render_replay(Path::new(replay), Path::new(out), &themes[0]),
+            _ => println!("Usage: --render <replay> <image.png>"),
+        }
+        return;
+    }
+
     let mut mp: Mp = Mp::new();
+    let mut game = Game::new(&mp);
 
-    let peer_states: Arc<Mutex<Vec<PlayerState>>> =
-        Arc::new(Mutex::new(vec![PlayerState::new(0); mp.id + 1]));
-    
-    let mut preserved_states: Vec<PlayerState> = Vec::new();
-    
-    let mut my_state: PlayerState = PlayerState::new(mp.id);
+    if text_only {
+        tui::run(&mut mp, &mut game, &themes[0]);
+        game.finish(&mut mp);
+        return;
+    }
 
     let mut window = Window::new("T3tropolis");
     window.set_light(Light::StickToCamera);
 
-    let mut drawer = Draw::new(&mut window);
-    my_state.begin();
+    let mut drawer = Draw::new(&mut window, themes);
 
-    let mut stream_read = mp.connection.try_clone().unwrap();
-    let data = peer_states.clone();
-
-    thread::spawn(move || {
-        loop {
-            let recv_adapter = NetworkAdapter::new_incoming(&mut stream_read);
-            let ps: PlayerState = recv_adapter.get_data();
-            let id = ps.id;
-            let mut ps_vec = data.lock().unwrap();
-            if id < (*ps_vec).len() {
-                (*ps_vec)[id] = ps;
-            }
-            else { (*ps_vec).push(ps); }
-        }
-    });
-
-    let mut t1 = SystemTime::now();
+    let mut chat_entry = ChatEntry::new();
 
     let mut mouse_pos: (f64, f64) = (0.0, 0.0);
-    let mut mouse_press_pos: (f64, f64) = (0.0, 0.0);
     let mut rotate_board = false;
 
-    let mut saved_shape: Option<Shape> = None;
-    let mut last_score: u32 = 0;
-    
     while window.render() {
         
-        let mut states: Vec<PlayerState> = Vec::new();
-
-        let data = peer_states.clone();
-        let ps_vec = data.lock().unwrap();
-        let num_peers = (*ps_vec).len();
-        for i in 0..(*ps_vec).len() {
-            if i != mp.id {
-                states.push((*ps_vec)[i].clone());
-            }
-            else { states.push(my_state.clone()); }
+        if game.poll(&mut mp) {
+            // the boards have swapped already, so start a quarter turn back
+            // and let the animation bring them round
+            drawer.orientation.prepend_rotation_mut(
+                &Vector3::new(0.0, std::f32::consts::PI / 2.0, 0.0));
+            drawer.anim_rot(-(std::f32::consts::PI / 2.0), ROTATION_FRAMES);
         }
 
-        check_target_swap(&mut my_state, &mut states, &mut saved_shape);
+        let states = game.states();
+        let ids: Vec<usize> = states.iter().map(|s| s.id).collect();
+        let my_id = game.my_id;
 
-        let score = check_rot(&mut my_state, &mut states,
-                              &mut preserved_states, &mut last_score,
-                              &mut drawer, &mut window);
+        let score = game.update(&mut mp, &states);
 
-        drawer.draw(&mut window, &states, mp.id, score);
+        let view_id = my_id.unwrap_or(drawer.focus);
+        drawer.push_events(game.my_state.take_events());
+        if !states.is_empty() {
+            drawer.draw(&mut window, &states, view_id, score);
+        }
+        if let Some(id) = my_id {
+            drawer.draw_picker(&mut window, &states, id);
+        }
+        if !states.is_empty() {
+            let (done, goal) = game.rot_clock.progress(&states);
+            drawer.draw_rotation(&mut window, &game.rot_clock.label(&states), done, goal);
+            drawer.draw_hud(&mut window, &states, view_id);
+        }
+        if let Some(text) = game.status() {
+            drawer.draw_status(&mut window, text);
+        }
+        drawer.draw_chat(&mut window, game.chat_log.lines(), &chat_entry.text);
+        if drawer.show_netstats || !mp.connected {
+            drawer.draw_netstats(&mut window, &game.net_health);
+        }
 
+        let width = window.width();
         for mut event in window.events().iter() {
             match event.value {
-                WindowEvent::Key(code, _, Action::Press, _) => {
+                WindowEvent::Key(code, _, Action::Press, _) if chat_entry.is_open() => {
                     match code {
-                        Key::W | Key::Up =>
-                            my_state.rotate_tetromino(),
-                        Key::S | Key::Down =>
-                            my_state.move_down(),
-                        Key::A | Key::Left =>
-                            my_state.move_left(),
-                        Key::D | Key::Right =>
-                            my_state.move_right(),
-                        Key::P =>
-                            my_state.paused = !my_state.paused,
-                        Key::F => {
-                            drawer.anim_rot(std::f32::consts::PI / 8.0, 10);
-                        },                            
-                        Key::CapsLock => {
-                            drawer.anim_rot(std::f32::consts::PI / -8.0, 10);
+                        Key::Enter => {
+                            if let Some(text) = chat_entry.submit() {
+                                mp.send_chat(text);
+                            }
                         },
-                        Key::Space =>
-                            my_state.drop(),
-                        Key::E =>
-                            my_state.toggle_swap(1 as isize, num_peers as isize),
-                        Key::C =>
-                            my_state.toggle_swap(-1 as isize, num_peers as isize),
+                        Key::Escape => chat_entry.cancel(),
+                        Key::Backspace => chat_entry.backspace(),
                         _ => (),
                     }
-                    mp.issue_update(my_state.clone());
+                    event.inhibited = true
+                },
+                WindowEvent::Char(c) if chat_entry.is_open() => {
+                    chat_entry.push_char(c);
+                    event.inhibited = true
+                },
+                WindowEvent::Key(Key::F12, _, Action::Press, _) if !states.is_empty() => {
+                    let secs = SystemTime::now().duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_secs()).unwrap_or(0);
+                    let path = format!("screenshot-{}.png", secs);
+                    match drawer.screenshot(&window, &states, view_id, Path::new(&path)) {
+                        Ok(()) => println!("Saved {}", path),
+                        Err(e) => println!("Couldn't save screenshot: {}", e),
+                    }
+                    event.inhibited = true
+                },
+                WindowEvent::Key(Key::Enter, _, Action::Press, _) => {
+                    chat_entry.open();
+                    event.inhibited = true
+                },
+                WindowEvent::Key(code, _, Action::Press, _) => {
+                    match my_id {
+                        Some(_) => {
+                            handle_game_key(code, &mut game.my_state, &mut drawer,
+                                            &mut game.trade, &states);
+                            mp.issue_update(game.my_state.clone());
+                        },
+                        None => handle_spectator_key(code, &mut drawer, &ids),
+                    }
 
                     event.inhibited = true // override the default keyboard handler
                 },
                 WindowEvent::MouseButton(_, Action::Press, _) => {
-                    rotate_board = true;
-                    mouse_press_pos = mouse_pos;
+                    // clicking a row of the trade picker offers to that player
+                    let row = my_id.and_then(|id| drawer.picker.row_at(
+                        mouse_pos.0 as f32, mouse_pos.1 as f32, width, id, &ids));
+                    match row {
+                        Some(target) => {
+                            drawer.picker.cursor = Some(target);
+                            drawer.picker.choose(&mut game.my_state);
+                            mp.issue_update(game.my_state.clone());
+                        },
+                        None => rotate_board = true,
+                    }
                     event.inhibited = true // override the default mouse handler
                 },
                 WindowEvent::MouseButton(_, Action::Release, _) => {
                     rotate_board = false;
                     event.inhibited = true // override the default mouse handler
                 },
+                WindowEvent::Scroll(_, dy) if drawer.picker.open => {
+                    if let Some(id) = my_id {
+                        drawer.picker.step(if dy > 0.0 { -1 } else { 1 }, id, &ids);
+                    }
+                    event.inhibited = true
+                },
+                WindowEvent::Scroll(_, dy) => {
+                    drawer.camera.zoom(dy as f32);
+                    event.inhibited = true
+                },
                 WindowEvent::CursorPos(x, y) => {
-                    mouse_pos = (x, y);
+                    // dragging turns the view, across for yaw and up and
+                    // down for pitch
                     if rotate_board {
-                        drawer.orientation.prepend_rotation_mut(
-                            &Vector3::new(0.0, ((mouse_pos.1 - mouse_press_pos.1) /
-                                                1000.0) as f32,
-                                          0.0));
+                        drawer.camera.turn(((x - mouse_pos.0) / 200.0) as f32,
+                                           ((y - mouse_pos.1) / 200.0) as f32);
                     }
+                    mouse_pos = (x, y);
 
                     event.inhibited = true // override the default mouse handler
                 },
                 _ => (),
             }
         }
-        if let Ok(d) = SystemTime::now().duration_since(t1) {
-            if d.as_secs() > 0.5 as u64 {
-                my_state.move_down();
-                t1 = SystemTime::now();
-                mp.issue_update(my_state.clone());
-            }
-        }
+        game.step(&mut mp, &states);
     }
+    game.finish(&mut mp);
 }
 
-fn check_target_swap(my_state: &mut PlayerState, states: &mut Vec<PlayerState>,
-                     saved_shape: &mut Option<Shape>) {
-    match my_state.next_tetromino.2.clone() {
-        TradeState::NoTrade => (),
-        TradeState::Pending(target) =>
-        {
-            *saved_shape = Some(states[target].next_tetromino.0);
-            match states[target].next_tetromino.2.clone() {
-                TradeState::NoTrade => (),
-                TradeState::Pending(id) => {
-                    if id == my_state.id {
-                        my_state.next_tetromino.2 = TradeState::Confirm(target);
-                    }
-                },
-                TradeState::Confirm(id) => {
-                    if id == my_state.id {
-                        my_state.next_tetromino.2 = TradeState::Confirm(target);
-                    }
-                    else {
-                        my_state.next_tetromino.2 = TradeState::NoTrade;
-                    }
-                },
-            }
-        },
-        TradeState::Confirm(target) =>
-        {
-            match states[target].next_tetromino.2.clone() {
-                TradeState::NoTrade => make_trade(my_state, saved_shape),
-                TradeState::Pending(id) => {
-                    if id != my_state.id {
-                        *saved_shape = None;
-                        my_state.next_tetromino.2 = TradeState::NoTrade;
-                    }
-                },
-                TradeState::Confirm(id) => {
-                    if id == my_state.id {
-                        make_trade(my_state, saved_shape);
-                    }
-                    else {
-                        my_state.next_tetromino.2 = TradeState::NoTrade;
-                    }
-                },
-            }
+fn handle_game_key(code: Key, my_state: &mut PlayerState, drawer: &mut Draw,
+                   trade: &mut TradeSession, states: &Vec<PlayerState>) {
+    let ids: Vec<usize> = states.iter().map(|s| s.id).collect();
+    match code {
+        Key::W | Key::Up =>
+            my_state.rotate_tetromino(),
+        Key::S | Key::Down =>
+            my_state.move_down(),
+        Key::A | Key::Left =>
+            my_state.move_left(),
+        Key::D | Key::Right =>
+            my_state.move_right(),
+        Key::P =>
+            my_state.paused = !my_state.paused,
+        Key::V =>
+            my_state.rotate_vote = !my_state.rotate_vote,
+        Key::Space =>
+            my_state.drop(),
+        Key::Tab =>
+            drawer.picker.toggle(my_state, &ids),
+        Key::E if drawer.picker.open =>
+            drawer.picker.step(1, my_state.id, &ids),
+        Key::C if drawer.picker.open =>
+            drawer.picker.step(-1, my_state.id, &ids),
+        Key::R if drawer.picker.open =>
+            drawer.picker.toggle_ring(),
+        Key::T if drawer.picker.open && drawer.picker.ring.len() + 1 >= RING_MIN => {
+            trade.propose_ring(my_state, &drawer.picker.ring);
+            drawer.picker.ring.clear();
         },
+        Key::T if drawer.picker.open =>
+            drawer.picker.choose(my_state),
+        Key::Escape if drawer.picker.open =>
+            drawer.picker.open = false,
+        Key::E =>
+            my_state.toggle_swap(1 as isize, &ids),
+        Key::C =>
+            my_state.toggle_swap(-1 as isize, &ids),
+        Key::Y =>
+            trade.accept(my_state, states),
+        Key::Z =>
+            trade.decline(my_state, states),
+        Key::X =>
+            trade.withdraw(my_state),
+        _ => handle_view_key(code, drawer),
     }
 }
 
-fn make_trade(my_state: &mut PlayerState, saved_shape: &mut Option<Shape>) {
-    if let &mut Some(shape) = saved_shape {
-        my_state.next_tetromino.0 = shape;
-        my_state.next_tetromino.1 = 0;
-        my_state.next_tetromino.2 = TradeState::NoTrade;
+fn handle_spectator_key(code: Key, drawer: &mut Draw, ids: &Vec<usize>) {
+    match code {
+        Key::E | Key::Right =>
+            drawer.cycle_focus(1, ids),
+        Key::C | Key::Left =>
+            drawer.cycle_focus(-1, ids),
+        _ => handle_view_key(code, drawer),
     }
-    *saved_shape = None;
 }
 
-fn check_rot(my_state: &mut PlayerState, cur_states: &mut Vec<PlayerState>,
-             preserved_states: &mut Vec<PlayerState>, last_score: &mut u32,
-             drawer: &mut Draw, window: &mut Window) -> u32 {
-
-    let score = cur_states.iter().fold(0, |acc, &ref x| acc + x.score);
-    
-    match my_state.board_state.clone() {
-        BoardState::Stable => {
-            if score - *last_score >= 50 * (cur_states.len() as u32) {
-                *last_score = score;
-                my_state.paused = true;
-                my_state.board_state = BoardState::Ready;
-            }
+fn handle_view_key(code: Key, drawer: &mut Draw) {
+    match code {
+        Key::F => {
+            drawer.anim_rot(std::f32::consts::PI / 8.0, 10);
+        },                            
+        Key::CapsLock => {
+            drawer.anim_rot(std::f32::consts::PI / -8.0, 10);
         },
-        BoardState::Ready => {
-            if cur_states.iter().all(|&ref x| x.board_state != BoardState::Stable) {
-                *preserved_states = cur_states.clone();
-                my_state.board_state = BoardState::Confirm;
-            }
+        Key::N =>
+            drawer.show_netstats = !drawer.show_netstats,
+        Key::G =>
+            drawer.ghost = drawer.ghost.next(),
+        Key::B =>
+            drawer.toggle_layout(),
+        Key::M =>
+            drawer.camera.set_mode(drawer.camera.mode.next()),
+        Key::PageUp =>
+            drawer.camera.turn(0.0, -0.1),
+        Key::PageDown =>
+            drawer.camera.turn(0.0, 0.1),
+        Key::Home =>
+            drawer.snap_back(),
+        Key::L =>
+            drawer.toggle_edges(),
+        Key::K =>
+            drawer.next_theme(),
+        _ => (),
+    }
+}
+
+// draws the last frame of a replay from the first player's side, straight to
+// an image and without opening a window
+fn render_replay(replay_path: &Path, out: &Path, theme: &Theme) {
+    let replay = match Replay::load(replay_path) {
+        Ok(replay) => replay,
+        Err(e) => {
+            println!("Couldn't load replay: {:?}", e);
+            return;
         },
-        BoardState::Confirm => {
-            if cur_states.iter().all(|&ref x| x.board_state != BoardState::Ready) {
-                drawer.anim_rot(-(std::f32::consts::PI / 2.0), 30);
-                while drawer.animating() {
-                    window.render();
-                    drawer.draw(window, preserved_states, my_state.id, score);
-                }
-                my_state.rotate_board(preserved_states);
-                my_state.board_state = BoardState::Stable;
-                my_state.new_tetromino();
-                drawer.orientation.prepend_rotation_mut(
-                    &Vector3::new(0.0, std::f32::consts::PI / 2.0, 0.0));
-                my_state.paused = false;
-            }
+    };
+    let states = match replay.frames.last() {
+        Some(frame) if !frame.states.is_empty() => &frame.states,
+        _ => {
+            println!("Nothing to render, the replay has no players");
+            return;
         },
+    };
+    let view = CameraRig::new().transform(&Isometry3::one());
+    let image = raster::render(states, states[0].id, theme, &view,
+                               RENDER_SIZE.0, RENDER_SIZE.1);
+    if let Err(e) = image.save_png(out) {
+        println!("Couldn't save image: {}", e);
     }
-    score
 }
@@ -1,29 +1,203 @@
 use std::net::TcpStream;
+use std::sync::mpsc::{Receiver, TryRecvError};
 
 use networkadapter::*;
 
-use playerstate::PlayerState;
+use playerstate::{PlayerState, NAME_MAX_LEN};
+use protocol::{ClientMessage, ServerMessage, RoomSettings, ROOM_NAME_MAX_LEN};
+use rotation::RotationTrigger;
+use trade::TradeRules;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Role {
+    Player(usize),
+    Spectator,
+}
 
 pub struct Mp {
-    pub connection: TcpStream,
-    pub id: usize,
+    pub role: Role,
+    pub room: usize,
+    pub settings: RoomSettings,
+    pub name: String,
+    outgoing: ClientSender,
+    incoming: Receiver<ServerMessage>,
+    pub connected: bool,
 }
 
 impl Mp {
     pub fn new() -> Mp {
         to_host_or_not();
-        let mut cnx = connect_to_server().unwrap();
-        //let mut stream_read = cnx.try_clone().unwrap();
-        let recv_adapter = NetworkAdapter::new_incoming(&mut cnx);
-        let id: usize = recv_adapter.get_data();
-        Mp {
-            connection: cnx,
-            id: id,
+        println!("Your name: ");
+        let name: String = get_input().chars().take(NAME_MAX_LEN).collect();
+        loop {
+            match Mp::join(name.clone()) {
+                Ok(mp) => return mp,
+                Err(e) => println!("Lost connection to server: {:?}", e),
+            }
         }
     }
 
+    fn join(name: String) -> Result<Mp, NetError> {
+        let mut cnx = try!(connect_to_server());
+        let max_frame = try!(welcome(&mut cnx));
+        let (room, role, settings) = try!(choose_room(&mut cnx, max_frame));
+        let (outgoing, incoming) = spawn_client_loop(cnx, max_frame);
+        Ok(Mp {
+            role: role,
+            room: room,
+            settings: settings,
+            name: name,
+            outgoing: outgoing,
+            incoming: incoming,
+            connected: true,
+        })
+    }
+
+    // everything the network thread has received since the last call
+    pub fn poll(&mut self) -> Vec<ServerMessage> {
+        let mut msgs = Vec::new();
+        loop {
+            match self.incoming.try_recv() {
+                Ok(msg) => msgs.push(msg),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    self.connected = false;
+                    break;
+                },
+            }
+        }
+        msgs
+    }
+
     pub fn issue_update(&mut self, ps: PlayerState) {
-        let adapter = NetworkAdapter::new_outgoing(ps);
-        send_data(&mut self.connection, adapter);
+        if self.role != Role::Spectator {
+            self.send(ClientMessage::State(ps));
+        }
+    }
+
+    pub fn send_chat(&mut self, text: String) {
+        self.send(ClientMessage::Chat(text));
+    }
+
+    pub fn pong(&mut self, seq: u32) {
+        self.send(ClientMessage::Pong(seq));
+    }
+
+    pub fn send_trade(&mut self, msg: ClientMessage) {
+        if self.role != Role::Spectator {
+            self.send(msg);
+        }
+    }
+
+    pub fn leave_room(&mut self) {
+        self.send(ClientMessage::LeaveRoom);
+    }
+
+    fn send(&mut self, msg: ClientMessage) {
+        let _ = self.outgoing.send(msg);
+    }
+}
+
+// the server's frame limit, announced before anything else; the greeting
+// itself is small enough for the default limit
+fn welcome(cnx: &mut TcpStream) -> Result<u32, NetError> {
+    match try!(next_reply(cnx, DEFAULT_MAX_FRAME)) {
+        ServerMessage::Welcome(max_frame) => Ok(max_frame),
+        msg => Err(NetError::Invalid(format!("expected a welcome, got {:?}", msg))),
+    }
+}
+
+fn choose_room(cnx: &mut TcpStream, max_frame: u32)
+               -> Result<(usize, Role, RoomSettings), NetError> {
+    loop {
+        try!(send_data(cnx, NetworkAdapter::new_outgoing(ClientMessage::ListRooms)));
+        if let ServerMessage::RoomList(rooms) = try!(next_reply(cnx, max_frame)) {
+            println!("Rooms:");
+            for room in rooms {
+                println!("  {}: {} ({} players, {} spectators, rotates {}, rings {})",
+                         room.id, room.name, room.players, room.spectators,
+                         room.settings.rotation.describe(),
+                         if room.settings.trade.rings { "on" } else { "off" });
+            }
+        }
+
+        println!("Enter a room number to join, 's <number>' to spectate, \
+                  or a name to create a new room: ");
+        let input = get_input();
+        let msg = match input.parse::<usize>() {
+            Ok(room_id) => ClientMessage::JoinRoom(room_id),
+            Err(_) => {
+                let spectate = if input.starts_with("s ") {
+                    input[2..].trim().parse::<usize>().ok()
+                } else { None };
+                match spectate {
+                    Some(room_id) => ClientMessage::SpectateRoom(room_id),
+                    None if input.chars().count() > ROOM_NAME_MAX_LEN => {
+                        println!("Room names are at most {} characters", ROOM_NAME_MAX_LEN);
+                        continue;
+                    },
+                    None => ClientMessage::CreateRoomWith(input, choose_settings()),
+                }
+            },
+        };
+        try!(send_data(cnx, NetworkAdapter::new_outgoing(msg)));
+
+        let joined = match try!(next_reply(cnx, max_frame)) {
+            ServerMessage::Joined(room, id) => Some((room, Role::Player(id))),
+            ServerMessage::Spectating(room) => Some((room, Role::Spectator)),
+            ServerMessage::Error(e) => { println!("{}", e); None },
+            _ => None,
+        };
+        if let Some((room, role)) = joined {
+            let settings = match try!(next_reply(cnx, max_frame)) {
+                ServerMessage::Settings(settings) => settings,
+                _ => RoomSettings::default(),
+            };
+            return Ok((room, role, settings));
+        }
+    }
+}
+
+fn choose_settings() -> RoomSettings {
+    println!("Rotate the board on 'score N' (points per player), 'time N' (seconds), \
+              'lines N', 'pieces N' or 'vote' [score 50]: ");
+    let input = get_input();
+    let mut words = input.split_whitespace();
+    let kind = words.next().unwrap_or("");
+    let n = words.next().and_then(|w| w.parse::<u32>().ok());
+    let rotation = match (kind, n) {
+        ("score", Some(n)) => RotationTrigger::Score(n),
+        ("time", Some(n)) => RotationTrigger::Interval(n),
+        ("lines", Some(n)) => RotationTrigger::Lines(n),
+        ("pieces", Some(n)) => RotationTrigger::Pieces(n),
+        ("vote", _) => RotationTrigger::Vote,
+        _ => RotationTrigger::default(),
+    };
+    // the server turns away what it won't accept, so ask for the default
+    let rotation = match rotation.check() {
+        Ok(()) => rotation,
+        Err(e) => {
+            println!("{}, rotating {} instead", e, RotationTrigger::default().describe());
+            RotationTrigger::default()
+        },
+    };
+    println!("Allow trade rings of three or more players? (y/n) [y]: ");
+    let rings = get_input() != "n";
+    RoomSettings {
+        rotation: rotation,
+        trade: TradeRules {
+            rings: rings,
+            ..TradeRules::default()
+        },
+    }
+}
+
+// skips heartbeats that arrive while waiting on a lobby reply
+fn next_reply(cnx: &mut TcpStream, max_frame: u32) -> Result<ServerMessage, NetError> {
+    loop {
+        match try!(receive(cnx, max_frame)) {
+            ServerMessage::Ping(_) => (),
+            msg => return Ok(msg),
+        }
     }
 }
@@ -0,0 +1,115 @@
+use std::time::{Duration, Instant};
+
+pub const PING_INTERVAL_MS: u64 = 1000;
+pub const LAG_TIMEOUT_MS: u64 = 3000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LinkReport {
+    pub rtt_ms: u32,
+    pub jitter_ms: u32,
+    pub pings_sent: u32,
+    pub pongs_received: u32,
+    pub lagging: bool,
+}
+
+// server-side view of one connection
+pub struct LinkStats {
+    last_seen: Instant,
+    // outstanding pings by sequence number
+    pending: Vec<(u32, Instant)>,
+    next_seq: u32,
+    rtt_ms: Option<f32>,
+    jitter_ms: f32,
+    pings_sent: u32,
+    pongs_received: u32,
+}
+
+fn millis(d: Duration) -> f32 {
+    d.as_secs() as f32 * 1000.0 + d.subsec_nanos() as f32 / 1_000_000.0
+}
+
+impl LinkStats {
+    pub fn new() -> LinkStats {
+        LinkStats {
+            last_seen: Instant::now(),
+            pending: Vec::new(),
+            next_seq: 0,
+            rtt_ms: None,
+            jitter_ms: 0.0,
+            pings_sent: 0,
+            pongs_received: 0,
+        }
+    }
+
+    pub fn seen(&mut self) {
+        self.last_seen = Instant::now();
+    }
+
+    pub fn ping(&mut self) -> u32 {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.pings_sent += 1;
+        // pings older than the lag timeout are counted as lost
+        let timeout = Duration::from_millis(LAG_TIMEOUT_MS);
+        self.pending.retain(|&(_, t)| t.elapsed() < timeout);
+        self.pending.push((seq, Instant::now()));
+        seq
+    }
+
+    pub fn pong(&mut self, seq: u32) {
+        if let Some(i) = self.pending.iter().position(|&(s, _)| s == seq) {
+            let (_, sent) = self.pending.remove(i);
+            let sample = millis(sent.elapsed());
+            self.pongs_received += 1;
+            // smoothed as in TCP's srtt and RFC 3550's interarrival jitter
+            self.rtt_ms = Some(match self.rtt_ms {
+                Some(rtt) => {
+                    self.jitter_ms += ((sample - rtt).abs() - self.jitter_ms) / 16.0;
+                    rtt + (sample - rtt) / 8.0
+                },
+                None => sample,
+            });
+        }
+    }
+
+    pub fn is_lagging(&self) -> bool {
+        self.last_seen.elapsed() > Duration::from_millis(LAG_TIMEOUT_MS)
+    }
+
+    pub fn report(&self) -> LinkReport {
+        LinkReport {
+            rtt_ms: self.rtt_ms.unwrap_or(0.0) as u32,
+            jitter_ms: self.jitter_ms as u32,
+            pings_sent: self.pings_sent,
+            pongs_received: self.pongs_received,
+            lagging: self.is_lagging(),
+        }
+    }
+}
+
+// client-side view of the room, filled in from server messages
+pub struct NetHealth {
+    // indexed by player id
+    pub peers: Vec<Option<LinkReport>>,
+    pub last_heard: Instant,
+}
+
+impl NetHealth {
+    pub fn new() -> NetHealth {
+        NetHealth {
+            peers: Vec::new(),
+            last_heard: Instant::now(),
+        }
+    }
+
+    pub fn update(&mut self, id: usize, report: LinkReport) {
+        while self.peers.len() <= id {
+            self.peers.push(None);
+        }
+        self.peers[id] = Some(report);
+    }
+
+    pub fn server_stalled(&self) -> bool {
+        self.last_heard.elapsed() > Duration::from_millis(LAG_TIMEOUT_MS)
+    }
+}
@@ -1,20 +1,53 @@
 use std::sync::mpsc;
-use std::sync::mpsc::{Sender, Receiver};
-use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{Sender, Receiver, TryRecvError};
+use std::time::{Duration, Instant};
+use std::collections::{HashMap, VecDeque};
+use std::net::{TcpListener, TcpStream, Shutdown};
+use mio;
+use mio::{Poll, Events, Token, Ready, PollOpt, Registration, SetReadiness};
 use std::thread;
 use std::io;
 use std::io::{stdout, stdin};
 use std::io::prelude::*;
 use std::marker::PhantomData;
-use bincode::SizeLimit;
-use bincode::rustc_serialize::{encode, decode};
-use rustc_serialize::{Encodable, Decodable};
+use schema;
+use schema::{Versioned, SchemaError, Kind};
 
-use playerstate::PlayerState;
+use protocol::{ClientMessage, ServerMessage, room_capacity, min_frame};
+use room::Lobby;
+
+// frames larger than this are rejected unless the host picks another limit
+pub const DEFAULT_MAX_FRAME: u32 = 64 * 1024;
+// clients whose unsent backlog grows past this are dropped
+pub const MAX_QUEUED_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub enum NetError {
+    Io(io::Error),
+    FrameTooShort(u32),
+    FrameTooLong(u32),
+    Schema(SchemaError),
+    Invalid(String),
+    Closed,
+    Backlog(usize),
+    Kicked,
+}
+
+impl From<io::Error> for NetError {
+    fn from(e: io::Error) -> NetError {
+        NetError::Io(e)
+    }
+}
+
+impl From<SchemaError> for NetError {
+    fn from(e: SchemaError) -> NetError {
+        NetError::Schema(e)
+    }
+}
 
 #[derive(Debug)]
 pub struct NetworkAdapter<T> 
-    where T: Encodable + Decodable
+    where T: Versioned
 {
     pub length: u32,
     pub data: Vec<u8>,
@@ -22,27 +55,33 @@ pub struct NetworkAdapter<T>
 }
 
 impl<T> NetworkAdapter<T> 
-    where T: Encodable + Decodable
+    where T: Versioned
 {
-    pub fn new_incoming(stream: &mut TcpStream) -> Self {
-        let length: u32 = read_length(stream);
+    pub fn new_incoming(stream: &mut TcpStream, max_len: u32)
+                        -> Result<Self, NetError> {
+        let length: u32 = try!(read_length(stream));
 
-        assert!(length > 4);
+        if length <= 4 {
+            return Err(NetError::FrameTooShort(length));
+        }
+        if length > max_len {
+            return Err(NetError::FrameTooLong(length));
+        }
         let read_length = (length as usize) - 4;
         let mut data: Vec<u8> = vec![0; read_length];
-        stream.read_exact(&mut data.as_mut_slice()).unwrap();
-        NetworkAdapter {
+        try!(stream.read_exact(&mut data.as_mut_slice()));
+        Ok(NetworkAdapter {
             length: length,
             data: data,
             phantom: PhantomData::<T>
-        }
+        })
     }
 
     pub fn new_outgoing(data: T) -> Self {
-        let mut data = encode(&data, SizeLimit::Infinite).unwrap();
+        let mut data = schema::encode(Kind::Message, &data).unwrap();
         let length = (4 + data.len()) as u32;
         if length < 5 {panic!("data can't be zero")}
-        let mut len = encode(&length, SizeLimit::Infinite).unwrap();
+        let mut len = encode_length(length).to_vec();
         len.append(&mut data);
         let data = len;
         NetworkAdapter {
@@ -52,79 +91,440 @@ impl<T> NetworkAdapter<T>
         }
     }
 
-    pub fn get_data(&self) -> T {
-        decode(&self.data[..]).unwrap()
+    pub fn get_data(&self) -> Result<T, NetError> {
+        Ok(try!(schema::decode(Kind::Message, &self.data[..])))
     }
 
 }
 
-fn read_length(stream: &mut TcpStream) -> u32 {
+// frame lengths are big-endian and include the 4 length bytes themselves
+fn encode_length(length: u32) -> [u8; 4] {
+    [(length >> 24) as u8, (length >> 16) as u8, (length >> 8) as u8, length as u8]
+}
+
+fn decode_length(buf: &[u8]) -> u32 {
+    ((buf[0] as u32) << 24) | ((buf[1] as u32) << 16) |
+    ((buf[2] as u32) << 8) | (buf[3] as u32)
+}
+
+fn read_length(stream: &mut TcpStream) -> Result<u32, NetError> {
     let mut buf: [u8; 4] = [0; 4];
-    stream.read_exact(&mut buf).unwrap();
-    decode(&buf).unwrap()
+    try!(stream.read_exact(&mut buf));
+    Ok(decode_length(&buf))
 }
 
-enum NetworkEvent {
-    NewConnection(usize, TcpStream),
-    NewMessage(usize, PlayerState),
+// reads one frame and decodes it, validating the result
+pub fn receive<T>(stream: &mut TcpStream, max_len: u32) -> Result<T, NetError>
+    where T: Versioned + Validate
+{
+    let adapter = try!(NetworkAdapter::new_incoming(stream, max_len));
+    let data: T = try!(adapter.get_data());
+    try!(data.validate().map_err(NetError::Invalid));
+    Ok(data)
 }
 
-fn handle_stream(mut stream: TcpStream, id: usize, tx: Sender<NetworkEvent>) {
-    thread::spawn(move|| {
+pub trait Validate {
+    fn validate(&self) -> Result<(), String>;
+}
+
+// accumulates bytes from a non-blocking stream and splits them into frames
+pub struct FrameReader {
+    buf: Vec<u8>,
+    max_len: u32,
+}
+
+impl FrameReader {
+    pub fn new(max_len: u32) -> FrameReader {
+        FrameReader {
+            buf: Vec::new(),
+            max_len: max_len,
+        }
+    }
+
+    // reads whatever is available without blocking
+    pub fn fill<S: Read>(&mut self, stream: &mut S) -> Result<(), NetError> {
+        let mut chunk = [0u8; 4096];
+        while self.buf.len() < self.max_len as usize {
+            match stream.read(&mut chunk) {
+                Ok(0) => return Err(NetError::Closed),
+                Ok(n) => self.buf.extend_from_slice(&chunk[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(NetError::Io(e)),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn next<T>(&mut self) -> Result<Option<T>, NetError>
+        where T: Versioned + Validate
+    {
+        if self.buf.len() < 4 {
+            return Ok(None);
+        }
+        let length: u32 = decode_length(&self.buf[..4]);
+        if length <= 4 {
+            return Err(NetError::FrameTooShort(length));
+        }
+        if length > self.max_len {
+            return Err(NetError::FrameTooLong(length));
+        }
+        if self.buf.len() < length as usize {
+            return Ok(None);
+        }
+        let frame: Vec<u8> = self.buf.drain(..length as usize).collect();
+        let data: T = try!(schema::decode(Kind::Message, &frame[4..]));
+        try!(data.validate().map_err(NetError::Invalid));
+        Ok(Some(data))
+    }
+}
+
+// frames waiting to be written to a non-blocking stream
+pub struct Outbound {
+    // frames keyed by player id are state updates that a newer one supersedes
+    frames: VecDeque<(Option<usize>, Vec<u8>)>,
+    // bytes of the front frame already written
+    written: usize,
+    queued: usize,
+}
+
+impl Outbound {
+    pub fn new() -> Outbound {
+        Outbound {
+            frames: VecDeque::new(),
+            written: 0,
+            queued: 0,
+        }
+    }
+
+    pub fn push(&mut self, key: Option<usize>, data: Vec<u8>) {
+        if key.is_some() {
+            // only coalesce past the last unkeyed frame so state updates
+            // never overtake control messages like PlayerLeft
+            let first = if self.written > 0 { 1 } else { 0 };
+            let mut i = self.frames.len();
+            while i > first && self.frames[i - 1].0.is_some() {
+                i -= 1;
+                if self.frames[i].0 == key {
+                    self.queued = self.queued - self.frames[i].1.len() + data.len();
+                    self.frames[i].1 = data;
+                    return;
+                }
+            }
+        }
+        self.queued += data.len();
+        self.frames.push_back((key, data));
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued
+    }
+
+    pub fn flush<S: Write>(&mut self, stream: &mut S) -> Result<(), NetError> {
         loop {
-            let recv_adapter = NetworkAdapter::new_incoming(&mut stream);
-            let data: PlayerState = recv_adapter.get_data();
-            tx.send(NetworkEvent::NewMessage(id, data));
+            let done = match self.frames.front() {
+                None => return Ok(()),
+                Some(&(_, ref data)) => {
+                    match stream.write(&data[self.written..]) {
+                        Ok(0) => return Err(NetError::Closed),
+                        Ok(n) => {
+                            self.written += n;
+                            self.written == data.len()
+                        },
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock =>
+                            return Ok(()),
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => false,
+                        Err(e) => return Err(NetError::Io(e)),
+                    }
+                },
+            };
+            if done {
+                let (_, data) = self.frames.pop_front().unwrap();
+                self.queued -= data.len();
+                self.written = 0;
+            }
         }
-    });
+    }
+}
+
+fn state_key(msg: &ServerMessage) -> Option<usize> {
+    match *msg {
+        ServerMessage::State(ref ps) => Some(ps.id),
+        _ => None,
+    }
+}
+
+struct Client {
+    stream: mio::net::TcpStream,
+    reader: FrameReader,
+    outbound: Outbound,
+    // kicked, with until when we keep trying to get the reason to it
+    closing: Option<Instant>,
+    // what the poller is watching this client for
+    interest: Ready,
+}
+
+impl Client {
+    fn wanted(&self) -> Ready {
+        let mut ready = if self.closing.is_none() { Ready::readable() } else { Ready::empty() };
+        if self.outbound.queued() > 0 {
+            ready = ready | Ready::writable();
+        }
+        ready
+    }
 }
 
+const LISTENER: Token = Token(0);
+// how often the lobby ticks when no socket has anything to say
+const TICK_MS: u64 = 100;
+// how long a kicked client gets to take the message saying why
+const LINGER_MS: u64 = 1000;
+
+fn client_token(id: usize) -> Token {
+    Token(id + 1)
+}
 
-pub fn create_server(host_port: String) {
-    let (tx, rx): (Sender<NetworkEvent>, Receiver<NetworkEvent>) = mpsc::channel();
+pub fn create_server(host_port: String, max_frame: u32) {
     let addr: String = "0.0.0.0:".to_string() + &host_port;
     println!("Creating a servers at {}", addr);
     let listener = TcpListener::bind(&addr as &str).unwrap();
-    // handle incoming connections
-    thread::spawn(move|| {
-        let mut id = 0;
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let tx = tx.clone();
-                    tx.send(
-                        NetworkEvent::NewConnection(
-                            id, stream.try_clone().unwrap()));
-                    handle_stream(stream, id, tx);
-                    id += 1;
-                },
-                Err(e) => println!("{:?}", e)
+    thread::spawn(move|| serve(listener, max_frame));
+}
+
+// one thread waits on every socket at once; the lobby only ever sees whole
+// messages and hands back what to send, so a slow client can't stall the
+// others
+fn serve(listener: TcpListener, max_frame: u32) {
+    let listener = mio::net::TcpListener::from_std(listener).unwrap();
+    let poll = Poll::new().unwrap();
+    poll.register(&listener, LISTENER, Ready::readable(), PollOpt::level()).unwrap();
+    let mut events = Events::with_capacity(256);
+    let mut lobby = Lobby::new(max_frame);
+    let mut clients: HashMap<usize, Client> = HashMap::new();
+    let mut next_id = 0;
+    loop {
+        if let Err(e) = poll.poll(&mut events, Some(Duration::from_millis(TICK_MS))) {
+            if e.kind() != io::ErrorKind::Interrupted {
+                println!("Server poll failed: {:?}", e);
+                return;
+            }
+        }
+
+        let mut dropped: Vec<(usize, NetError)> = Vec::new();
+        for event in events.iter() {
+            if event.token() == LISTENER {
+                loop {
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            let token = client_token(next_id);
+                            if poll.register(&stream, token, Ready::readable(),
+                                             PollOpt::level()).is_ok() {
+                                clients.insert(next_id, Client {
+                                    stream: stream,
+                                    reader: FrameReader::new(max_frame),
+                                    outbound: Outbound::new(),
+                                    closing: None,
+                                    interest: Ready::readable(),
+                                });
+                                lobby.connect(next_id);
+                                next_id += 1;
+                            }
+                        },
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            println!("{:?}", e);
+                            break;
+                        },
+                    }
+                }
+                continue;
+            }
+
+            let id = event.token().0 - 1;
+            let client = match clients.get_mut(&id) {
+                Some(client) => client,
+                None => continue,
+            };
+            if !event.readiness().is_readable() || client.closing.is_some() {
+                continue;
+            }
+            let filled = client.reader.fill(&mut client.stream);
+            loop {
+                match client.reader.next() {
+                    Ok(Some(msg)) => lobby.handle(id, msg),
+                    Ok(None) => break,
+                    Err(e) => {
+                        dropped.push((id, e));
+                        break;
+                    },
+                }
+            }
+            if let Err(e) = filled {
+                dropped.push((id, e));
+            }
+        }
+
+        lobby.tick();
+
+        for (id, msg) in lobby.drain_outbox() {
+            if let Some(client) = clients.get_mut(&id) {
+                let key = state_key(&msg);
+                client.outbound.push(key, NetworkAdapter::new_outgoing(msg).data);
+            }
+        }
+        for id in lobby.drain_kicked() {
+            if let Some(client) = clients.get_mut(&id) {
+                client.closing = Some(Instant::now() + Duration::from_millis(LINGER_MS));
+            }
+        }
+        for (&id, client) in clients.iter_mut() {
+            if let Err(e) = client.outbound.flush(&mut client.stream) {
+                dropped.push((id, e));
+            }
+            else if client.outbound.queued() > MAX_QUEUED_BYTES {
+                dropped.push((id, NetError::Backlog(client.outbound.queued())));
+            }
+            else if let Some(deadline) = client.closing {
+                // gone once the reason is out, or when it won't go
+                if client.outbound.queued() == 0 || Instant::now() >= deadline {
+                    dropped.push((id, NetError::Kicked));
+                }
             }
         }
-    });
 
+        for (id, e) in dropped {
+            if let Some(client) = clients.remove(&id) {
+                println!("Dropping client {}: {:?}", id, e);
+                let _ = poll.deregister(&client.stream);
+                let _ = client.stream.shutdown(Shutdown::Both);
+                lobby.disconnect(id);
+            }
+        }
+
+        for (&id, client) in clients.iter_mut() {
+            let wanted = client.wanted();
+            if wanted != client.interest {
+                let _ = poll.reregister(&client.stream, client_token(id), wanted,
+                                        PollOpt::level());
+                client.interest = wanted;
+            }
+        }
+    }
+}
+
+// the game thread's end of the client loop: sending wakes the loop up
+pub struct ClientSender {
+    tx: Option<Sender<ClientMessage>>,
+    wake: SetReadiness,
+}
+
+impl ClientSender {
+    pub fn send(&self, msg: ClientMessage) -> Result<(), ()> {
+        match self.tx {
+            Some(ref tx) => try!(tx.send(msg).map_err(|_| ())),
+            None => return Err(()),
+        }
+        self.wake.set_readiness(Ready::readable()).map_err(|_| ())
+    }
+}
+
+impl Drop for ClientSender {
+    // hangs up first, so the woken loop finds the channel closed and stops
+    fn drop(&mut self) {
+        self.tx.take();
+        let _ = self.wake.set_readiness(Ready::readable());
+    }
+}
+
+const SOCKET: Token = Token(0);
+const OUTGOING: Token = Token(1);
+
+// runs the client's socket on its own thread, the game thread talks to it
+// over channels and never blocks on the network
+pub fn spawn_client_loop(stream: TcpStream, max_frame: u32)
+                         -> (ClientSender, Receiver<ServerMessage>) {
+    let (out_tx, out_rx) = mpsc::channel::<ClientMessage>();
+    let (in_tx, in_rx) = mpsc::channel::<ServerMessage>();
+    let (registration, wake) = Registration::new2();
+    let woken = wake.clone();
     thread::spawn(move|| {
-        let mut conns: Vec<TcpStream> = vec![];
+        let mut stream = mio::net::TcpStream::from_stream(stream).unwrap();
+        let poll = Poll::new().unwrap();
+        poll.register(&stream, SOCKET, Ready::readable(), PollOpt::level()).unwrap();
+        poll.register(&registration, OUTGOING, Ready::readable(), PollOpt::edge()).unwrap();
+        let mut events = Events::with_capacity(16);
+        let mut reader = FrameReader::new(max_frame);
+        let mut outbound = Outbound::new();
+        let mut interest = Ready::readable();
+        // errors are passed on as if from the server rather than printed,
+        // the front-end may have the terminal in raw mode
         loop {
-            // handle sending pp to all streams
-            let event = rx.recv().unwrap();
-            match event {
-                NetworkEvent::NewConnection(id, stream) => {
-                    conns.push(stream);
-                    let adapter = NetworkAdapter::new_outgoing(id);
-                    conns[id].write(adapter.data.as_slice());
-                },
-                NetworkEvent::NewMessage(id, data) => {
-                    let adapter = NetworkAdapter::new_outgoing(data);
-                    for (idx, ref mut conn) in conns.iter_mut().enumerate() {
-                        if id != idx {
-                            conn.write_all(adapter.data.as_slice());
-                        }
+            if let Err(e) = poll.poll(&mut events, None) {
+                if e.kind() != io::ErrorKind::Interrupted {
+                    let _ = in_tx.send(ServerMessage::Error(
+                        format!("Client poll failed: {:?}", e)));
+                    return;
+                }
+            }
+
+            // clear the wake-up before draining, so a send that lands in
+            // between wakes us again rather than being missed
+            let _ = woken.set_readiness(Ready::empty());
+            loop {
+                match out_rx.try_recv() {
+                    Ok(msg) => {
+                        let key = match msg {
+                            ClientMessage::State(ref ps) => Some(ps.id),
+                            _ => None,
+                        };
+                        outbound.push(key, NetworkAdapter::new_outgoing(msg).data);
+                    },
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            }
+            if let Err(e) = outbound.flush(&mut stream) {
+                let _ = in_tx.send(ServerMessage::Error(
+                    format!("Lost connection to server: {:?}", e)));
+                return;
+            }
+
+            if events.iter().any(|e| e.token() == SOCKET && e.readiness().is_readable()) {
+                let filled = reader.fill(&mut stream);
+                loop {
+                    match reader.next() {
+                        Ok(Some(msg)) => {
+                            if in_tx.send(msg).is_err() {
+                                return;
+                            }
+                        },
+                        Ok(None) => break,
+                        Err(e) => {
+                            let _ = in_tx.send(ServerMessage::Error(
+                                format!("Bad message from server: {:?}", e)));
+                            return;
+                        },
                     }
                 }
+                if let Err(e) = filled {
+                    let _ = in_tx.send(ServerMessage::Error(
+                        format!("Lost connection to server: {:?}", e)));
+                    return;
+                }
+            }
+
+            let wanted = if outbound.queued() > 0 {
+                Ready::readable() | Ready::writable()
+            } else {
+                Ready::readable()
+            };
+            if wanted != interest {
+                let _ = poll.reregister(&stream, SOCKET, wanted, PollOpt::level());
+                interest = wanted;
             }
         }
     });
+    (ClientSender { tx: Some(out_tx), wake: wake }, in_rx)
 }
 
 pub fn connect_to_server() -> io::Result<TcpStream> {
@@ -136,9 +536,9 @@ pub fn connect_to_server() -> io::Result<TcpStream> {
 }
 
 pub fn send_data<T>(stream: &mut TcpStream, 
-                    adapter: NetworkAdapter<T>) 
-    where T: Encodable + Decodable {
-    stream.write_all(adapter.data.as_slice());
+                    adapter: NetworkAdapter<T>) -> io::Result<()>
+    where T: Versioned {
+    stream.write_all(adapter.data.as_slice())
 }
 
 pub fn to_host_or_not() {
@@ -152,13 +552,20 @@ pub fn to_host_or_not() {
         "y" => {
             print!("Host port: ");
             let port = get_input();
-            create_server(port);
+            println!("Max frame size in bytes (blank for {}): ", DEFAULT_MAX_FRAME);
+            let mut max_frame = get_input().parse::<u32>().unwrap_or(DEFAULT_MAX_FRAME);
+            if max_frame < min_frame() {
+                println!("Too small for two players, using {}", min_frame());
+                max_frame = min_frame();
+            }
+            println!("Rooms hold up to {} players", room_capacity(max_frame));
+            create_server(port, max_frame);
         }
         _ => return
     }
 }
 
-fn get_input() -> String {
+pub fn get_input() -> String {
     print!("> ");
     let mut input = String::new();
     let _ = stdout().flush();
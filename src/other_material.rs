@@ -1,4 +1,10 @@
 use std::ptr;
+use std::ffi::CString;
+use std::rc::Rc;
+use std::cell::Cell;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
 use gl;
 use gl::types::*;
 use nalgebra::{Point2, Point3, Vector3, Matrix3, Matrix4, Isometry3};
@@ -7,8 +13,10 @@ use kiss3d::resource::Matrixerial;
 use kiss3d::scene::ObjectData;
 use kiss3d::light::Light;
 use kiss3d::camera::Camera;
-use kiss3d::resource::{Mesh, Shader, ShaderAttribute, ShaderUniform};
+use kiss3d::resource::{Mesh, Shader, ShaderAttribute, ShaderUniform, GLPrimitive};
 
+// checking after every call stalls the pipeline, so only debug builds do it
+#[cfg(debug_assertions)]
 macro_rules! verify(
     ($e: expr) => {
         $e;
@@ -16,6 +24,53 @@ macro_rules! verify(
     }
 );
 
+#[cfg(not(debug_assertions))]
+macro_rules! verify(
+    ($e: expr) => {
+        $e;
+    }
+);
+
+// shaders here replace the built-in ones, both or neither
+const VERTEX_PATH: &'static str = "shaders/object.vert";
+const FRAGMENT_PATH: &'static str = "shaders/object.frag";
+
+/// How cubes are lit, shared by every material made with it so changes show
+/// up on the next frame.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct MaterialParams {
+    /// Alpha of the ambient term, which the lighting halves.
+    pub alpha: f32,
+    /// Scales the object's colour in the ambient term.
+    pub ambient: f32,
+    /// Strength of the highlight, 0 for none.
+    pub specular: f32,
+    /// How tight the highlight is.
+    pub shininess: f32,
+    /// Whether cube borders are picked out.
+    pub edges: bool,
+    /// How far the border is mixed toward `edge_color`.
+    pub edge_strength: f32,
+    /// Border width, as a fraction of the cube.
+    pub edge_width: f32,
+    pub edge_color: (f32, f32, f32),
+}
+
+impl Default for MaterialParams {
+    // the look before any of this was configurable
+    fn default() -> MaterialParams {
+        MaterialParams {
+            alpha: 0.8,
+            ambient: 1.0,
+            specular: 0.0,
+            shininess: 16.0,
+            edges: false,
+            edge_strength: 0.6,
+            edge_width: 0.06,
+            edge_color: (1.0, 1.0, 1.0),
+        }
+    }
+}
 
 /// The default material used to draw objects.
 pub struct MyObjectMatrixerial {
@@ -29,30 +84,75 @@ pub struct MyObjectMatrixerial {
     scale:      ShaderUniform<Matrix3<f32>>,
     ntransform: ShaderUniform<Matrix3<f32>>,
     view:       ShaderUniform<Matrix4<f32>>,
+    // the rest are optional, a modded shader can leave any of them out
+    eye:        Option<ShaderUniform<Point3<f32>>>,
+    opacity:    Option<ShaderUniform<f32>>,
+    amb_alpha:  Option<ShaderUniform<f32>>,
+    ambient:    Option<ShaderUniform<f32>>,
+    specular:   Option<ShaderUniform<f32>>,
+    shininess:  Option<ShaderUniform<f32>>,
+    edge:       Option<ShaderUniform<f32>>,
+    edge_width: Option<ShaderUniform<f32>>,
+    edge_color: Option<ShaderUniform<Point3<f32>>>,
     alpha:      bool,
+    // scales the alpha the lighting works out, when blending
+    opacity_value: f32,
+    params:     Rc<Cell<MaterialParams>>,
 }
 
 impl MyObjectMatrixerial {
-    /// Creates a new `MyObjectMatrixerial`.
-    pub fn new(allow_alpha: bool) -> MyObjectMatrixerial {
-        // load the shader
-        let mut shader = Shader::new_from_str(OBJECT_VERTEX_SRC, OBJECT_FRAGMENT_SRC);
+    /// Creates a new `MyObjectMatrixerial` from shaders already checked by
+    /// `ShaderSources::load`.
+    pub fn new(sources: &ShaderSources, allow_alpha: bool, opacity: f32,
+               params: Rc<Cell<MaterialParams>>) -> MyObjectMatrixerial {
+        let opacity = if allow_alpha { opacity } else { 1.0 };
+        MyObjectMatrixerial::build(&sources.vert, &sources.frag, allow_alpha, opacity, params)
+            .expect("shaders are checked when loaded")
+    }
+
+    // compiles the shaders and gets the variables locations, None if one
+    // the material can't do without is missing
+    fn build(vert: &str, frag: &str, allow_alpha: bool, opacity: f32,
+             params: Rc<Cell<MaterialParams>>) -> Option<MyObjectMatrixerial> {
+        let mut shader = Shader::new_from_str(vert, frag);
 
         shader.use_program();
 
-        // get the variables locations
-        MyObjectMatrixerial {
-            pos:        shader.get_attrib("position").unwrap(),
-            normal:     shader.get_attrib("normal").unwrap(),
-            tex_coord:  shader.get_attrib("tex_coord_v").unwrap(),
-            light:      shader.get_uniform("light_position").unwrap(),
-            color:      shader.get_uniform("color").unwrap(),
-            transform:  shader.get_uniform("transform").unwrap(),
-            scale:      shader.get_uniform("scale").unwrap(),
-            ntransform: shader.get_uniform("ntransform").unwrap(),
-            view:       shader.get_uniform("view").unwrap(),
-            shader:     shader,
-            alpha:      allow_alpha,
+        let [pos, normal, tex_coord] = REQUIRED_ATTRIBS;
+        let [light, color, transform, scale, ntransform, view] = REQUIRED_UNIFORMS;
+        let required = (shader.get_attrib(pos), shader.get_attrib(normal),
+                        shader.get_attrib(tex_coord),
+                        shader.get_uniform(light), shader.get_uniform(color),
+                        shader.get_uniform(transform), shader.get_uniform(scale),
+                        shader.get_uniform(ntransform), shader.get_uniform(view));
+        match required {
+            (Some(pos), Some(normal), Some(tex_coord), Some(light), Some(color),
+             Some(transform), Some(scale), Some(ntransform), Some(view)) =>
+                Some(MyObjectMatrixerial {
+                    pos:        pos,
+                    normal:     normal,
+                    tex_coord:  tex_coord,
+                    light:      light,
+                    color:      color,
+                    transform:  transform,
+                    scale:      scale,
+                    ntransform: ntransform,
+                    view:       view,
+                    eye:        shader.get_uniform("eye_position"),
+                    opacity:    shader.get_uniform("opacity"),
+                    amb_alpha:  shader.get_uniform("ambient_alpha"),
+                    ambient:    shader.get_uniform("ambient"),
+                    specular:   shader.get_uniform("specular"),
+                    shininess:  shader.get_uniform("shininess"),
+                    edge:       shader.get_uniform("edge"),
+                    edge_width: shader.get_uniform("edge_width"),
+                    edge_color: shader.get_uniform("edge_color"),
+                    shader:     shader,
+                    alpha:      allow_alpha,
+                    opacity_value: opacity,
+                    params:     params,
+                }),
+            _ => None,
         }
     }
 
@@ -68,6 +168,164 @@ impl MyObjectMatrixerial {
         self.normal.disable();
         self.tex_coord.disable();
     }
+
+    fn upload_params(&mut self) {
+        let params = self.params.get();
+        let edge = if params.edges { params.edge_strength } else { 0.0 };
+        let edge_color = Point3::new(params.edge_color.0, params.edge_color.1,
+                                     params.edge_color.2);
+        upload(&mut self.opacity, &self.opacity_value);
+        upload(&mut self.amb_alpha, &params.alpha);
+        upload(&mut self.ambient, &params.ambient);
+        upload(&mut self.specular, &params.specular);
+        upload(&mut self.shininess, &params.shininess);
+        upload(&mut self.edge, &edge);
+        upload(&mut self.edge_width, &params.edge_width);
+        upload(&mut self.edge_color, &edge_color);
+    }
+}
+
+fn upload<T: GLPrimitive>(uniform: &mut Option<ShaderUniform<T>>, value: &T) {
+    if let Some(ref mut uniform) = *uniform {
+        uniform.upload(value);
+    }
+}
+
+// the attributes and uniforms the material can't do without, checked when
+// the shaders load and looked up in this order when a material is built
+const REQUIRED_ATTRIBS: [&'static str; 3] = ["position", "normal", "tex_coord_v"];
+const REQUIRED_UNIFORMS: [&'static str; 6] = ["light_position", "color", "transform", "scale",
+                                              "ntransform", "view"];
+
+/// The shaders every material is built from, read and checked once.
+pub struct ShaderSources {
+    vert: String,
+    frag: String,
+}
+
+impl ShaderSources {
+    /// The shaders from `shaders/` if they compile, link and declare what
+    /// the game sets, otherwise the built-in ones. Needs a current GL
+    /// context.
+    pub fn load() -> ShaderSources {
+        if let Some((vert, frag)) = load_sources() {
+            match check_program(&vert, &frag) {
+                Ok(()) => return ShaderSources { vert: vert, frag: frag },
+                Err(e) => println!("{}, using the built-in shaders", e),
+            }
+        }
+        ShaderSources {
+            vert: OBJECT_VERTEX_SRC.to_string(),
+            frag: OBJECT_FRAGMENT_SRC.to_string(),
+        }
+    }
+}
+
+// compiles and links the shaders on their own, as kiss3d panics on the
+// first error rather than returning it
+fn check_program(vert: &str, frag: &str) -> Result<(), String> {
+    unsafe {
+        let vs = try!(compile(gl::VERTEX_SHADER, vert)
+                      .map_err(|e| format!("{} doesn't compile: {}", VERTEX_PATH, e)));
+        let fs = match compile(gl::FRAGMENT_SHADER, frag) {
+            Ok(fs) => fs,
+            Err(e) => {
+                gl::DeleteShader(vs);
+                return Err(format!("{} doesn't compile: {}", FRAGMENT_PATH, e));
+            },
+        };
+        let program = gl::CreateProgram();
+        gl::AttachShader(program, vs);
+        gl::AttachShader(program, fs);
+        gl::LinkProgram(program);
+        let mut status = gl::FALSE as GLint;
+        gl::GetProgramiv(program, gl::LINK_STATUS, &mut status);
+        let result = if status != gl::TRUE as GLint {
+            Err(format!("{} and {} don't link: {}", VERTEX_PATH, FRAGMENT_PATH,
+                        program_log(program)))
+        }
+        else {
+            missing_inputs(program).map_or(Ok(()), |name| {
+                Err(format!("{} and {} don't declare {}", VERTEX_PATH, FRAGMENT_PATH, name))
+            })
+        };
+        gl::DeleteProgram(program);
+        gl::DeleteShader(vs);
+        gl::DeleteShader(fs);
+        result
+    }
+}
+
+unsafe fn compile(kind: GLenum, source: &str) -> Result<GLuint, String> {
+    let source = try!(CString::new(source).map_err(|_| "it holds a NUL byte".to_string()));
+    let shader = gl::CreateShader(kind);
+    gl::ShaderSource(shader, 1, &source.as_ptr(), ptr::null());
+    gl::CompileShader(shader);
+    let mut status = gl::FALSE as GLint;
+    gl::GetShaderiv(shader, gl::COMPILE_STATUS, &mut status);
+    if status == gl::TRUE as GLint {
+        return Ok(shader);
+    }
+    let mut len = 0;
+    gl::GetShaderiv(shader, gl::INFO_LOG_LENGTH, &mut len);
+    let mut log = vec![0u8; len.max(1) as usize];
+    gl::GetShaderInfoLog(shader, len, ptr::null_mut(), log.as_mut_ptr() as *mut GLchar);
+    gl::DeleteShader(shader);
+    Err(info_log(log))
+}
+
+unsafe fn program_log(program: GLuint) -> String {
+    let mut len = 0;
+    gl::GetProgramiv(program, gl::INFO_LOG_LENGTH, &mut len);
+    let mut log = vec![0u8; len.max(1) as usize];
+    gl::GetProgramInfoLog(program, len, ptr::null_mut(), log.as_mut_ptr() as *mut GLchar);
+    info_log(log)
+}
+
+fn info_log(mut log: Vec<u8>) -> String {
+    if let Some(end) = log.iter().position(|&b| b == 0) {
+        log.truncate(end);
+    }
+    String::from_utf8_lossy(&log).trim().to_string()
+}
+
+// the first required input the linked program has no location for, a
+// declared but unused one is optimised out and counts as missing too
+unsafe fn missing_inputs(program: GLuint) -> Option<&'static str> {
+    for &name in REQUIRED_ATTRIBS.iter() {
+        let c_name = CString::new(name).unwrap();
+        if gl::GetAttribLocation(program, c_name.as_ptr()) == -1 {
+            return Some(name);
+        }
+    }
+    for &name in REQUIRED_UNIFORMS.iter() {
+        let c_name = CString::new(name).unwrap();
+        if gl::GetUniformLocation(program, c_name.as_ptr()) == -1 {
+            return Some(name);
+        }
+    }
+    None
+}
+
+// both shader files, or None to use the built-in shaders
+fn load_sources() -> Option<(String, String)> {
+    match (read_source(VERTEX_PATH), read_source(FRAGMENT_PATH)) {
+        (Some(vert), Some(frag)) => Some((vert, frag)),
+        (None, None) => None,
+        _ => {
+            println!("Need both {} and {}, using the built-in shaders",
+                     VERTEX_PATH, FRAGMENT_PATH);
+            None
+        },
+    }
+}
+
+fn read_source(path: &str) -> Option<String> {
+    let mut source = String::new();
+    match File::open(Path::new(path)) {
+        Ok(mut file) => file.read_to_string(&mut source).ok().map(|_| source),
+        Err(_) => None,
+    }
 }
 
 impl Matrixerial for MyObjectMatrixerial {
@@ -95,6 +353,8 @@ impl Matrixerial for MyObjectMatrixerial {
         };
 
         self.light.upload(&pos);
+        let eye = camera.eye();
+        upload(&mut self.eye, &eye);
 
         /*
          *
@@ -118,6 +378,7 @@ impl Matrixerial for MyObjectMatrixerial {
             self.ntransform.upload(&formated_ntransform);
             self.scale.upload(&formated_scale);
             self.color.upload(data.color());
+            self.upload_params();
 
             mesh.bind(&mut self.pos, &mut self.normal, &mut self.tex_coord);
 
@@ -186,6 +447,7 @@ const A_VERY_LONG_STRING: &'static str =
     varying vec3 ws_normal;
     varying vec3 ws_position;
     varying vec2 tex_coord;
+    varying vec3 os_position;
     uniform mat4 view;
     uniform mat4 transform;
     uniform mat3 scale;
@@ -194,6 +456,7 @@ const A_VERY_LONG_STRING: &'static str =
         mat4 scale4 = mat4(scale);
         vec4 pos4   = transform * scale4 * vec4(position, 1.0);
         tex_coord   = tex_coord_v;
+        os_position = position;
         ws_position = pos4.xyz;
         gl_Position = view * pos4;
         ws_normal   = normalize(ntransform * scale * normal);
@@ -201,26 +464,47 @@ const A_VERY_LONG_STRING: &'static str =
 
 // phong-like lighting (heavily) inspired
 // by http://www.opengl.org/sdk/docs/tutorials/ClockworkCoders/lighting.php
+// with a Blinn highlight, and cube borders found from the unit cube's own
+// coordinates: a fragment near two of its faces at once is on an edge.
 const ANOTHER_VERY_LONG_STRING: &'static str =
    "#version 120
     uniform vec3      color;
     uniform vec3      light_position;
+    uniform vec3      eye_position;
+    uniform float     opacity;
+    uniform float     ambient_alpha;
+    uniform float     ambient;
+    uniform float     specular;
+    uniform float     shininess;
+    uniform float     edge;
+    uniform float     edge_width;
+    uniform vec3      edge_color;
     uniform sampler2D tex;
     varying vec2      tex_coord;
     varying vec3      ws_normal;
     varying vec3      ws_position;
+    varying vec3      os_position;
     void main() {
       vec3 L = normalize(light_position - ws_position);
-      vec3 E = normalize(-ws_position);
+      vec3 E = normalize(eye_position - ws_position);
       //calculate Ambient Term:
-      vec4 Iamb = vec4(color, 0.8);
+      vec4 Iamb = vec4(color * ambient, ambient_alpha);
       //calculate Diffuse Term:
       vec4 Idiff1 = vec4(1.0, 1.0, 1.0, 1.0) * max(dot(ws_normal,L), 0.0);
       Idiff1 = clamp(Idiff1, 0.0, 1.0);
       // double sided lighting:
       vec4 Idiff2 = vec4(1.0, 1.0, 1.0, 1.0) * max(dot(-ws_normal,L), 0.0);
       Idiff2 = clamp(Idiff2, 0.0, 1.0);
+      //calculate Specular Term, on whichever side faces the light:
+      vec3 N = dot(ws_normal, L) < 0.0 ? -ws_normal : ws_normal;
+      vec3 H = normalize(L + E);
+      float Ispec = specular * pow(max(dot(N, H), 0.0), shininess);
       vec4 tex_color = texture2D(tex, tex_coord);
       gl_FragColor   = tex_color * (Iamb + (Idiff1 + Idiff2) / 2) / 2;
+      gl_FragColor.rgb += vec3(Ispec);
+      //highlight the borders:
+      vec3 d = 0.5 - abs(os_position);
+      float near = step(d.x, edge_width) + step(d.y, edge_width) + step(d.z, edge_width);
+      gl_FragColor.rgb = mix(gl_FragColor.rgb, edge_color, edge * step(2.0, near));
+      gl_FragColor.a = gl_FragColor.a * opacity;
     }";
-
@@ -0,0 +1,162 @@
+use playerstate::{PlayerState, TradeState};
+
+// next pieces shown at once before the carousel starts scrolling
+pub const CAROUSEL_SLOTS: usize = 5;
+
+// layout of the picker list, in the same pixel units as draw_text
+pub const PICKER_WIDTH: f32 = 360.0;
+pub const PICKER_TOP: f32 = 160.0;
+pub const PICKER_ROW: f32 = 35.0;
+// rows the list shows at once before it scrolls
+pub const PICKER_ROWS: usize = 8;
+
+// a browsable list of every other player's next piece, for choosing whom to
+// trade with in games too big for E and C
+pub struct TradePicker {
+    pub open: bool,
+    // player id under the cursor
+    pub cursor: Option<usize>,
+    // players picked for a trade ring, in passing order
+    pub ring: Vec<usize>,
+    // carousel position in slots relative to our own piece, eased toward
+    // whatever is being looked at
+    pub scroll: f32,
+    // index among the other players of the list's top row
+    top: usize,
+}
+
+fn others(my_id: usize, ids: &Vec<usize>) -> Vec<usize> {
+    ids.iter().cloned().filter(|&id| id != my_id).collect()
+}
+
+impl TradePicker {
+    pub fn new() -> TradePicker {
+        TradePicker {
+            open: false,
+            cursor: None,
+            ring: Vec::new(),
+            scroll: 0.0,
+            top: 0,
+        }
+    }
+
+    // opening starts the cursor on the current target, or whoever follows us
+    pub fn toggle(&mut self, my_state: &PlayerState, ids: &Vec<usize>) {
+        self.open = !self.open;
+        if self.open {
+            self.cursor = match my_state.next_tetromino.2 {
+                TradeState::Pending(target) => Some(target),
+                _ => None,
+            };
+            if self.cursor.is_none() {
+                self.step(1, my_state.id, ids);
+            }
+            self.follow_cursor(&others(my_state.id, ids));
+        }
+    }
+
+    pub fn step(&mut self, d: isize, my_id: usize, ids: &Vec<usize>) {
+        let others = others(my_id, ids);
+        if others.is_empty() {
+            self.cursor = None;
+            return;
+        }
+        let n = others.len() as isize;
+        self.cursor = Some(match self.cursor.and_then(|c| others.iter().position(|&id| id == c)) {
+            Some(cur) => others[((cur as isize + d + n) % n) as usize],
+            // start just after us in id order
+            None => *others.iter().find(|&&id| id > my_id).unwrap_or(&others[0]),
+        });
+        self.follow_cursor(&others);
+    }
+
+    // scrolls the list just far enough to show the cursor
+    fn follow_cursor(&mut self, others: &Vec<usize>) {
+        if let Some(pos) = self.cursor.and_then(|c| others.iter().position(|&id| id == c)) {
+            if pos < self.top {
+                self.top = pos;
+            }
+            else if pos >= self.top + PICKER_ROWS {
+                self.top = pos + 1 - PICKER_ROWS;
+            }
+        }
+    }
+
+    // index of the top row and the players whose rows are showing, the list
+    // kept full if players have left since it last scrolled
+    pub fn visible(&self, my_id: usize, ids: &Vec<usize>) -> (usize, Vec<usize>) {
+        let others = others(my_id, ids);
+        let top = self.top.min(others.len().saturating_sub(PICKER_ROWS));
+        (top, others.into_iter().skip(top).take(PICKER_ROWS).collect())
+    }
+
+    // offers our next piece to the player under the cursor, or takes the
+    // offer back if they already have it
+    pub fn choose(&mut self, my_state: &mut PlayerState) {
+        if my_state.paused {
+            return;
+        }
+        if let Some(target) = self.cursor {
+            my_state.next_tetromino.2 = match my_state.next_tetromino.2 {
+                TradeState::Pending(t) if t == target => TradeState::NoTrade,
+                _ => TradeState::Pending(target),
+            };
+        }
+    }
+
+    // adds the player under the cursor to the ring being put together, or
+    // takes them out again
+    pub fn toggle_ring(&mut self) {
+        if let Some(id) = self.cursor {
+            match self.ring.iter().position(|&r| r == id) {
+                Some(pos) => { self.ring.remove(pos); },
+                None => self.ring.push(id),
+            }
+        }
+    }
+
+    // the player whose row is at a screen position, given the window width
+    pub fn row_at(&self, x: f32, y: f32, width: f32, my_id: usize, ids: &Vec<usize>)
+                  -> Option<usize> {
+        if !self.open || x < width - PICKER_WIDTH || y < PICKER_TOP + PICKER_ROW {
+            return None;
+        }
+        let row = ((y - PICKER_TOP - PICKER_ROW) / PICKER_ROW) as usize;
+        self.visible(my_id, ids).1.get(row).cloned()
+    }
+
+    // eases the carousel toward a slot offset, a fraction of the way per frame
+    pub fn scroll_to(&mut self, offset: f32) {
+        self.scroll += (offset - self.scroll) * 0.2;
+        if (offset - self.scroll).abs() < 0.01 {
+            self.scroll = offset;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_list_scrolls_to_keep_the_cursor_showing() {
+        let ids: Vec<usize> = (0..20).collect();
+        let mut picker = TradePicker::new();
+        picker.toggle(&PlayerState::new(0), &ids);
+        assert_eq!(picker.visible(0, &ids), (0, (1..9).collect()));
+        for _ in 0..10 {
+            picker.step(1, 0, &ids);
+        }
+        assert_eq!(picker.cursor, Some(11));
+        assert_eq!(picker.visible(0, &ids), (3, (4..12).collect()));
+        // the row clicked is the one drawn there
+        let y = PICKER_TOP + PICKER_ROW * 8.5;
+        assert_eq!(picker.row_at(1000.0, y, 1000.0, 0, &ids), Some(11));
+        // wrapping round to the first player scrolls back to the top
+        for _ in 0..9 {
+            picker.step(1, 0, &ids);
+        }
+        assert_eq!(picker.cursor, Some(1));
+        assert_eq!(picker.visible(0, &ids).0, 0);
+    }
+}
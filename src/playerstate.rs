@@ -1,9 +1,14 @@
+use std::mem;
+
 use rand::{OsRng, Rng};
 
+use events::GameEvent;
+
 pub const ROWS: usize = 22;
 pub const COLS: usize = 10;
+pub const NAME_MAX_LEN: usize = 16;
 
-#[derive(Copy, Clone, PartialEq, RustcDecodable, RustcEncodable, Debug)]
+#[derive(Copy, Clone, PartialEq, Serialize, Deserialize, Debug)]
 pub enum Cell {
     E, I, J, L, O, S, T, Z,
 }
@@ -164,7 +169,22 @@ pub const ZSHAPE: Shape = [
      [0, 0, 0, 0]]
 ];
 
-fn cell_of_shape(s: Shape) -> Cell {
+pub const SHAPES: [Shape; 7] = [ISHAPE, JSHAPE, LSHAPE, OSHAPE, SSHAPE, TSHAPE, ZSHAPE];
+
+pub fn shape_letter(s: Shape) -> char {
+    match s {
+        ISHAPE => 'I',
+        JSHAPE => 'J',
+        LSHAPE => 'L',
+        OSHAPE => 'O',
+        SSHAPE => 'S',
+        TSHAPE => 'T',
+        ZSHAPE => 'Z',
+        _ => '?',
+    }
+}
+
+pub fn cell_of_shape(s: Shape) -> Cell {
     match s {
         ISHAPE => Cell::I,
         JSHAPE => Cell::J,
@@ -177,21 +197,42 @@ fn cell_of_shape(s: Shape) -> Cell {
     }
 }
 
-#[derive(RustcDecodable, RustcEncodable, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum TradeState {
     NoTrade,
     Pending(usize),
-    Confirm(usize),
+    // in a proposed trade ring, members in passing order
+    Ring(Vec<usize>),
 }
 
-#[derive(RustcDecodable, RustcEncodable, Debug, Clone, PartialEq)]
+impl TradeState {
+    // whether this offer would hand a piece to the given player
+    pub fn offers_to(&self, id: usize) -> bool {
+        match *self {
+            TradeState::Pending(t) => t == id,
+            TradeState::Ring(ref members) => members.contains(&id),
+            _ => false,
+        }
+    }
+}
+
+// how long an open trade offer has left, shared so the partner can see it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TradeExpiry {
+    pub secs_left: f32,
+    pub drops_left: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum BoardState {
     Stable,
     Ready,
+    // was held while a rotation animated, no longer sent but kept so
+    // replays that recorded it still read
     Confirm,
 }
 
-#[derive(RustcDecodable, RustcEncodable, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PlayerState {
     pub paused: bool,
     pub board: [[Cell; COLS]; ROWS],
@@ -201,6 +242,33 @@ pub struct PlayerState {
     pub score: u32,
     pub board_state: BoardState,
     pub id: usize,
+    // pieces taken from the queue so far
+    #[serde(default)]
+    pub pieces: u32,
+    // lines cleared so far
+    #[serde(default)]
+    pub lines: u32,
+    // pieces locked onto the board so far
+    #[serde(default)]
+    pub locks: u32,
+    // asking for the board to rotate, in rooms that rotate on a vote
+    #[serde(default)]
+    pub rotate_vote: bool,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub trade_expiry: Option<TradeExpiry>,
+    // waiting to be taken by the drawing code, local only
+    #[serde(skip)]
+    pub events: Vec<GameEvent>,
+    // whether the last thing the falling piece did was turn, for T-spins
+    #[serde(skip)]
+    last_rotated: bool,
+}
+
+// rows from the floor up to the highest filled cell
+fn stack_height(board: &[[Cell; COLS]; ROWS]) -> usize {
+    board.iter().rposition(|row| row.iter().any(|&c| c != Cell::E)).map_or(0, |r| r + 1)
 }
 
 impl PlayerState {
@@ -214,9 +282,35 @@ impl PlayerState {
             score: 0,
             board_state: BoardState::Stable,
             id: id,
+            pieces: 0,
+            lines: 0,
+            locks: 0,
+            rotate_vote: false,
+            name: String::new(),
+            trade_expiry: None,
+            events: Vec::new(),
+            last_rotated: false,
         }
     }
 
+    // sanity checks for states arriving off the wire
+    pub fn check(&self) -> Result<(), String> {
+        if !SHAPES.contains(&self.tetromino.0) || !SHAPES.contains(&self.next_tetromino.0) {
+            return Err("unknown tetromino shape".to_string());
+        }
+        if self.name.chars().count() > NAME_MAX_LEN {
+            return Err("player name too long".to_string());
+        }
+        if self.tetromino.1 >= 4 || self.next_tetromino.1 >= 4 {
+            return Err("tetromino rotation out of range".to_string());
+        }
+        let (r, c) = self.tetro_pos;
+        if r < -4 || r >= ROWS as i8 || c < -4 || c >= COLS as i8 {
+            return Err(format!("tetromino position {:?} out of range", self.tetro_pos));
+        }
+        Ok(())
+    }
+
     pub fn begin(&mut self) {
         self.select_next_shape();
         self.new_tetromino();
@@ -248,36 +342,82 @@ impl PlayerState {
     }
 
     pub fn new_tetromino(&mut self) {
+        let trade = self.next_tetromino.2.clone();
         self.tetromino.0 = self.next_tetromino.0;
         self.tetromino.1 = self.next_tetromino.1;
         self.select_next_shape();
+        self.pieces += 1;
+
+        // a pending offer or ring carries over to the new next piece until
+        // it runs out of drops
+        let mut carried = false;
+        if trade != TradeState::NoTrade {
+            if let Some(ref mut expiry) = self.trade_expiry {
+                if expiry.drops_left > 1 {
+                    expiry.drops_left -= 1;
+                    self.next_tetromino.2 = trade;
+                    carried = true;
+                }
+            }
+        }
+        if !carried {
+            self.trade_expiry = None;
+        }
     }
 
-    pub fn toggle_swap(&mut self, d: isize, n: isize) {
+    // moves the offer of our next piece on to the next player in id order,
+    // withdrawing it once it comes back round to us. We're always in the
+    // cycle, so we're never offered to ourselves.
+    pub fn toggle_swap(&mut self, d: isize, ids: &Vec<usize>) {
         if !self.paused {
-            let target: usize = ((self.id as isize + d + n) % n) as usize;
-            match self.next_tetromino.2 {
-                TradeState::NoTrade =>
-                    self.next_tetromino.2 = TradeState::Pending(target),
-                TradeState::Pending(id) => {
-                    if id == target {
-                        self.next_tetromino.2 = TradeState::NoTrade;
-                    }
-                    else {
-                        self.next_tetromino.2 = TradeState::Pending(target);
-                    }
-                },
-                TradeState::Confirm(_) => (),            
+            let mut cycle = ids.clone();
+            if !cycle.contains(&self.id) {
+                cycle.push(self.id);
+                cycle.sort();
             }
+            let from = match self.next_tetromino.2 {
+                TradeState::Pending(id) if cycle.contains(&id) => id,
+                _ => self.id,
+            };
+            let n = cycle.len() as isize;
+            let cur = cycle.iter().position(|&id| id == from).unwrap() as isize;
+            let target = cycle[((cur + d + n) % n) as usize];
+            self.next_tetromino.2 =
+                if target == self.id { TradeState::NoTrade }
+                else { TradeState::Pending(target) };
         }
     }
 
+    // swaps in a traded shape for the piece we offered when `pieces` was seq,
+    // whether it's still next or already falling. False if it has locked,
+    // when nothing stands in for it.
+    pub fn receive_piece(&mut self, seq: u32, shape: Shape) -> bool {
+        if self.pieces == seq {
+            self.next_tetromino.0 = shape;
+            self.next_tetromino.1 = 0;
+        }
+        else if self.pieces == seq + 1 {
+            self.tetromino = (shape, 0);
+            if self.collision(0, 0) {
+                self.tetro_pos = (ROWS as i8 - 3, COLS as i8 / 2 - 1);
+            }
+        }
+        else {
+            return false;
+        }
+        self.events.push(GameEvent::TradeCompleted(shape));
+        true
+    }
+
     pub fn rotate_tetromino(&mut self) {
         if !self.paused {
             self.tetromino.1 = (self.tetromino.1 + 1) % 4;
             if self.collision(0,0) {
                 self.tetromino.1 = (self.tetromino.1 + 3) % 4;
             }
+            else {
+                self.last_rotated = true;
+            }
         }
     }
 
@@ -287,24 +427,78 @@ impl PlayerState {
             
 	    if self.collision(0,0) {
 	        self.tetro_pos.0 += 1;
+	        let tspin = self.tspin();
 	        self.tetro_to_board();
-	        self.clear_lines();
+	        let lines = self.clear_lines();
+	        if tspin {
+	            self.events.push(GameEvent::TSpin { lines: lines });
+	        }
+	        self.last_rotated = false;
 	        self.tetro_pos = (ROWS as i8 - 3, COLS as i8 / 2 - 1);
  	        self.new_tetromino();
 	    }
+	    else {
+	        self.last_rotated = false;
+	    }
         }
     }
 
+    pub fn level(&self) -> u32 {
+        self.lines / 10 + 1
+    }
+
     pub fn drop(&mut self) {
         if !self.paused {
-            while !self.collision(-1, 0) {
-                self.tetro_pos.0 -= 1;
+            let landing = self.landing_pos();
+            if landing != self.tetro_pos {
+                self.last_rotated = false;
             }
+            self.tetro_pos = landing;
         }
     }
 
-    fn clear_lines(&mut self) {
+    // takes our board back after a rotation
+    pub fn rotated_to(&mut self, board: [[Cell; COLS]; ROWS]) {
+        let before = stack_height(&self.board);
+        self.board = board;
+        self.events.push(GameEvent::BoardRotated);
+        let after = stack_height(&self.board);
+        if after > before {
+            self.events.push(GameEvent::GarbageReceived { rows: (before..after).collect() });
+        }
+    }
+
+    pub fn take_events(&mut self) -> Vec<GameEvent> {
+        mem::replace(&mut self.events, Vec::new())
+    }
+
+    // a T that turned into place with at least three of the four corners
+    // around its centre blocked, walls and floor counting as blocked
+    fn tspin(&self) -> bool {
+        if self.tetromino.0 != TSHAPE || !self.last_rotated {
+            return false;
+        }
+        let corners = [(0, 0), (0, 2), (2, 0), (2, 2)];
+        let blocked = corners.iter().filter(|&&(dr, dc)| {
+            let (r, c) = (self.tetro_pos.0 + dr, self.tetro_pos.1 + dc);
+            r < 0 || r >= ROWS as i8 || c < 0 || c >= COLS as i8 ||
+                self.board[r as usize][c as usize] != Cell::E
+        }).count();
+        blocked >= 3
+    }
+
+    // where the falling piece would come to rest if dropped now
+    pub fn landing_pos(&self) -> (i8, i8) {
+        let mut dr = 0;
+        while !self.collision(dr - 1, 0) {
+            dr -= 1;
+        }
+        (self.tetro_pos.0 + dr, self.tetro_pos.1)
+    }
+
+    fn clear_lines(&mut self) -> u32 {
         let mut lines_cleared: u32 = 0;
+        let mut rows = Vec::new();
         let mut clear_line = true;
         for i in 0..ROWS {
 	    clear_line = true;
@@ -316,10 +510,16 @@ impl PlayerState {
             }
 	    if clear_line {
                 lines_cleared += 1;
+                rows.push(ROWS - i - 1);
 	    	self.delete_line(ROWS - i - 1);
 	    }
         }
+        self.lines += lines_cleared;
         self.score += 2u32.pow(lines_cleared * 2);
+        if !rows.is_empty() {
+            self.events.push(GameEvent::LinesCleared { rows: rows });
+        }
+        lines_cleared
     }
 
     fn delete_line(&mut self, line: usize) {
@@ -337,6 +537,9 @@ impl PlayerState {
 	    if self.collision(0,0) {
 	        self.tetro_pos.1 += 1;
 	    }
+	    else {
+	        self.last_rotated = false;
+	    }
         }
     }
 
@@ -347,10 +550,14 @@ impl PlayerState {
 	    if self.collision(0,0) {
 	        self.tetro_pos.1 -= 1;
 	    }
+	    else {
+	        self.last_rotated = false;
+	    }
         }
     }
 
     fn tetro_to_board(&mut self) {
+        let mut cells = Vec::new();
         for i in 0..4 {
 	    for j in 0..4 {
 	        if self.tetromino.0[self.tetromino.1][i][j] != 0 {
@@ -358,6 +565,7 @@ impl PlayerState {
                                   (j as i8 + self.tetro_pos.1) as usize);
                     if r < ROWS - 2 && self.board[r][c] == Cell::E {
 		        self.board[r][c] = cell_of_shape(self.tetromino.0);
+                        cells.push((r, c));
                     }
                     else {
                         self.reset();
@@ -365,9 +573,11 @@ impl PlayerState {
 	        }
             }
         }
+        self.locks += 1;
+        self.events.push(GameEvent::PieceLocked(cells));
     }
 
-    fn collision(&mut self, dr: i8, dc: i8) -> bool {
+    fn collision(&self, dr: i8, dc: i8) -> bool {
         let nr = self.tetro_pos.0 + dr;
         let nc = self.tetro_pos.1 + dc;
 
@@ -389,22 +599,36 @@ impl PlayerState {
         }
         false
     }
+}
 
-    pub fn rotate_board(&mut self, preserved_states: &Vec<PlayerState>) {
-        println!("ROTATE BOARD!!");
-        let l = preserved_states.len();
-        let col_offset = self.id * COLS / l;
-        let state_start = self.id * COLS % l;
-        for c in 0..COLS {
-            for r in 0..ROWS {
-                println!("new board[{}][{}] = ", r, c);
-                println!("taking from player id: {}", (state_start + c) % l);
-                println!("at row {}", r);
-                println!(" at col {}", COLS - 1 - col_offset - ((state_start + c) / l));
-                self.board[r][c] =
-                    preserved_states[(state_start + c) % l].
-                    board[r][COLS - 1 - col_offset - ((state_start + c) / l)];
-            }
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alone_in_a_room_there_is_nobody_to_offer_to() {
+        let mut ps = PlayerState::new(0);
+        ps.toggle_swap(1, &vec![0]);
+        assert_eq!(ps.next_tetromino.2, TradeState::NoTrade);
+        ps.toggle_swap(-1, &vec![0]);
+        assert_eq!(ps.next_tetromino.2, TradeState::NoTrade);
+        // nor when our own id is missing from the list
+        ps.toggle_swap(1, &vec![]);
+        assert_eq!(ps.next_tetromino.2, TradeState::NoTrade);
+    }
+
+    #[test]
+    fn offers_go_round_the_others_and_back_to_nobody() {
+        let mut ps = PlayerState::new(1);
+        let ids = vec![0, 1];
+        ps.toggle_swap(1, &ids);
+        assert_eq!(ps.next_tetromino.2, TradeState::Pending(0));
+        ps.toggle_swap(1, &ids);
+        assert_eq!(ps.next_tetromino.2, TradeState::NoTrade);
+        ps.toggle_swap(-1, &ids);
+        assert_eq!(ps.next_tetromino.2, TradeState::Pending(0));
+        // an offer to someone who has left starts again from us
+        ps.toggle_swap(1, &vec![1, 2]);
+        assert_eq!(ps.next_tetromino.2, TradeState::Pending(2));
     }
 }
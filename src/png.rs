@@ -0,0 +1,183 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+// an 8-bit RGB image, rows top to bottom
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize, fill: (u8, u8, u8)) -> Image {
+        let mut pixels = Vec::with_capacity(width * height * 3);
+        for _ in 0..width * height {
+            pixels.push(fill.0);
+            pixels.push(fill.1);
+            pixels.push(fill.2);
+        }
+        Image {
+            width: width,
+            height: height,
+            pixels: pixels,
+        }
+    }
+
+    // mixes a colour into a pixel, alpha 1 covering it completely
+    pub fn blend(&mut self, x: usize, y: usize, color: (f32, f32, f32), alpha: f32) {
+        if x >= self.width || y >= self.height {
+            return;
+        }
+        let i = (y * self.width + x) * 3;
+        for (k, &c) in [color.0, color.1, color.2].iter().enumerate() {
+            let old = self.pixels[i + k] as f32 / 255.0;
+            let new = old * (1.0 - alpha) + c.max(0.0).min(1.0) * alpha;
+            self.pixels[i + k] = (new * 255.0).round() as u8;
+        }
+    }
+
+    pub fn save_png(&self, path: &Path) -> io::Result<()> {
+        let mut file = try!(File::create(path));
+        file.write_all(&self.encode_png())
+    }
+
+    // a plain PNG: one IDAT of stored (uncompressed) deflate blocks, no
+    // filtering, so there's nothing to it but framing and checksums
+    pub fn encode_png(&self) -> Vec<u8> {
+        let mut out = vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+        let mut ihdr = Vec::new();
+        push_u32(&mut ihdr, self.width as u32);
+        push_u32(&mut ihdr, self.height as u32);
+        // 8 bits a channel, truecolour, deflate, no filter, no interlace
+        ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+        chunk(&mut out, b"IHDR", &ihdr);
+
+        // every scanline starts with its filter type, 0 for none
+        let stride = self.width * 3;
+        let mut raw = Vec::with_capacity((stride + 1) * self.height);
+        for row in self.pixels.chunks(stride) {
+            raw.push(0);
+            raw.extend_from_slice(row);
+        }
+        chunk(&mut out, b"IDAT", &zlib_stored(&raw));
+        chunk(&mut out, b"IEND", &[]);
+        out
+    }
+}
+
+fn push_u32(out: &mut Vec<u8>, n: u32) {
+    out.push((n >> 24) as u8);
+    out.push((n >> 16) as u8);
+    out.push((n >> 8) as u8);
+    out.push(n as u8);
+}
+
+fn chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    push_u32(out, data.len() as u32);
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    push_u32(out, crc);
+}
+
+// a zlib stream of deflate blocks stored as they are, each at most 64K
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    const BLOCK: usize = 65535;
+    // deflate with a 32K window, no dictionary, header check bits to suit
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(BLOCK).peekable();
+    if blocks.peek().is_none() {
+        // an empty stream is still one final, empty block
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(if last { 1 } else { 0 });
+        out.extend_from_slice(&[len as u8, (len >> 8) as u8, !len as u8, (!len >> 8) as u8]);
+        out.extend_from_slice(block);
+    }
+    push_u32(&mut out, adler32(data));
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for n in 0..256 {
+        let mut c = n as u32;
+        for _ in 0..8 {
+            c = if c & 1 != 0 { 0xedb88320 ^ (c >> 1) } else { c >> 1 };
+        }
+        table[n] = c;
+    }
+    let mut crc = 0xffffffffu32;
+    for &byte in data {
+        crc = table[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc ^ 0xffffffff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_matches_known_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(b"IEND"), 0xae426082);
+    }
+
+    #[test]
+    fn adler32_matches_known_values() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+        assert_eq!(adler32(&[7; 70000]), 0xf3007a7a);
+    }
+
+    #[test]
+    fn an_empty_stream_is_one_empty_block() {
+        assert_eq!(zlib_stored(&[]),
+                   vec![0x78, 0x01, 0x01, 0x00, 0x00, 0xff, 0xff, 0x00, 0x00, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn long_streams_are_split_into_blocks() {
+        let out = zlib_stored(&[7; 70000]);
+        assert_eq!(&out[..7], &[0x78, 0x01, 0x00, 0xff, 0xff, 0x00, 0x00]);
+        let second = 7 + 65535;
+        assert_eq!(&out[second..second + 5], &[0x01, 0x71, 0x11, 0x8e, 0xee]);
+        assert_eq!(out.len(), second + 5 + 70000 - 65535 + 4);
+        assert_eq!(&out[out.len() - 4..], &[0xf3, 0x00, 0x7a, 0x7a]);
+    }
+
+    #[test]
+    fn encodes_a_single_pixel() {
+        let image = Image::new(1, 1, (255, 0, 0));
+        assert_eq!(image.encode_png(), vec![
+            0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a,
+            0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+            0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x02, 0x00, 0x00, 0x00,
+            0x90, 0x77, 0x53, 0xde,
+            0x00, 0x00, 0x00, 0x0f, 0x49, 0x44, 0x41, 0x54,
+            0x78, 0x01, 0x01, 0x04, 0x00, 0xfb, 0xff, 0x00, 0xff, 0x00, 0x00,
+            0x03, 0x01, 0x01, 0x00,
+            0x8d, 0x1d, 0xe5, 0x82,
+            0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44,
+            0xae, 0x42, 0x60, 0x82,
+        ]);
+    }
+}
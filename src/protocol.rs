@@ -0,0 +1,285 @@
+use playerstate::{PlayerState, TradeState};
+use netstats::LinkReport;
+use trade::{TradeCommit, RingCommit, TradeRules, RING_MAX};
+use networkadapter::Validate;
+use schema::Versioned;
+use chat::CHAT_MAX_LEN;
+use rotation::RotationTrigger;
+
+// in characters
+pub const ROOM_NAME_MAX_LEN: usize = 32;
+// an encoded player state that passes validation never grows past this
+pub const MAX_STATE_BYTES: u32 = 2560;
+// the length prefix and the envelope around a Rotate's states
+const ROTATE_OVERHEAD: u32 = 128;
+// an encoded room in the lobby's list never grows past this
+pub const MAX_ROOM_INFO_BYTES: u32 = 512;
+// the same around a RoomList's rooms
+const ROOM_LIST_OVERHEAD: u32 = 128;
+
+// players a room can hold before a Rotate carrying every board would no
+// longer fit in a frame
+pub fn room_capacity(max_frame: u32) -> usize {
+    (max_frame.saturating_sub(ROTATE_OVERHEAD) / MAX_STATE_BYTES) as usize
+}
+
+// rooms a RoomList can carry in one frame, the lobby lists no more
+pub fn room_list_capacity(max_frame: u32) -> usize {
+    (max_frame.saturating_sub(ROOM_LIST_OVERHEAD) / MAX_ROOM_INFO_BYTES) as usize
+}
+
+// the smallest frame limit that still lets two players share a room
+pub fn min_frame() -> u32 {
+    ROTATE_OVERHEAD + 2 * MAX_STATE_BYTES
+}
+
+// chosen by whoever creates a room, fixed for the room's lifetime
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct RoomSettings {
+    #[serde(default)]
+    pub rotation: RotationTrigger,
+    #[serde(default)]
+    pub trade: TradeRules,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RoomInfo {
+    pub id: usize,
+    pub name: String,
+    pub players: usize,
+    pub spectators: usize,
+    #[serde(default)]
+    pub settings: RoomSettings,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ClientMessage {
+    ListRooms,
+    // a room with default settings
+    CreateRoom(String),
+    CreateRoomWith(String, RoomSettings),
+    JoinRoom(usize),
+    SpectateRoom(usize),
+    LeaveRoom,
+    State(PlayerState),
+    Chat(String),
+    Pong(u32),
+    // target and our piece count when offering, the server knows which
+    // piece that is
+    TradeOffer(usize, u32),
+    TradeWithdraw,
+    // the player whose offer we turn down
+    TradeDecline(usize),
+    // members in passing order starting with us, then our piece count as
+    // above
+    RingPropose(Vec<usize>, u32),
+    // ring id and our piece count
+    RingJoin(usize, u32),
+    RingLeave(usize),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum TradeEnd {
+    Declined,
+    Expired,
+    Rejected,
+    PartnerLeft,
+}
+
+impl TradeEnd {
+    pub fn describe(&self) -> &'static str {
+        match *self {
+            TradeEnd::Declined => "was declined",
+            TradeEnd::Expired => "ran out of time",
+            TradeEnd::Rejected => "was rejected, the piece had moved on",
+            TradeEnd::PartnerLeft => "was called off, a player left",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum ServerMessage {
+    // sent on connect, the largest frame either side may send
+    Welcome(u32),
+    RoomList(Vec<RoomInfo>),
+    // room id, player id within the room
+    Joined(usize, usize),
+    Spectating(usize),
+    // follows Joined or Spectating
+    Settings(RoomSettings),
+    Left,
+    PlayerLeft(usize),
+    State(PlayerState),
+    // sender's player id, None for spectators
+    Chat(Option<usize>, String),
+    Ping(u32),
+    PeerStatus(usize, LinkReport),
+    TradeCommitted(TradeCommit),
+    // an offer of ours to the given player was closed without a trade
+    TradeClosed(usize, TradeEnd),
+    // sent to each member, ring id and members in passing order
+    RingProposed(usize, Vec<usize>),
+    RingCommitted(RingCommit),
+    RingClosed(usize, TradeEnd),
+    // every player is ready, these are the boards after the rotation
+    Rotate(Vec<PlayerState>),
+    Error(String),
+}
+
+impl Versioned for ClientMessage {}
+
+impl Versioned for ServerMessage {}
+
+impl Validate for ClientMessage {
+    fn validate(&self) -> Result<(), String> {
+        match *self {
+            ClientMessage::CreateRoomWith(_, ref settings) => {
+                try!(settings.rotation.check());
+                settings.trade.check()
+            },
+            ClientMessage::Chat(ref text) => {
+                if text.chars().count() > CHAT_MAX_LEN {
+                    return Err("chat message too long".to_string());
+                }
+                Ok(())
+            },
+            ClientMessage::State(ref ps) => {
+                if let TradeState::Ring(ref members) = ps.next_tetromino.2 {
+                    if members.len() > RING_MAX {
+                        return Err("trade ring too long".to_string());
+                    }
+                }
+                ps.check()
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Validate for ServerMessage {
+    fn validate(&self) -> Result<(), String> {
+        match *self {
+            ServerMessage::State(ref ps) => ps.check(),
+            ServerMessage::Rotate(ref states) => {
+                for ps in states.iter() {
+                    try!(ps.check());
+                }
+                Ok(())
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::iter;
+    use playerstate::*;
+    use networkadapter::DEFAULT_MAX_FRAME;
+    use schema;
+    use schema::Kind;
+
+    // every field as long as validation lets it get
+    fn largest_state() -> PlayerState {
+        let mut ps = PlayerState::new(usize::max_value());
+        ps.board = [[Cell::Z; COLS]; ROWS];
+        ps.tetro_pos = (-4, -4);
+        ps.next_tetromino.2 = TradeState::Ring(vec![usize::max_value(); RING_MAX]);
+        ps.score = u32::max_value();
+        ps.pieces = u32::max_value();
+        ps.lines = u32::max_value();
+        ps.locks = u32::max_value();
+        ps.board_state = BoardState::Confirm;
+        // control characters are escaped six bytes at a time
+        ps.name = iter::repeat('\u{1}').take(NAME_MAX_LEN).collect();
+        ps.trade_expiry = Some(TradeExpiry {
+            secs_left: -1.1754942e-38,
+            drops_left: u32::max_value(),
+        });
+        ps
+    }
+
+    // every field as long as the server lets it get
+    fn largest_room() -> RoomInfo {
+        RoomInfo {
+            id: usize::max_value(),
+            name: iter::repeat('\u{1}').take(ROOM_NAME_MAX_LEN).collect(),
+            players: usize::max_value(),
+            spectators: usize::max_value(),
+            settings: RoomSettings {
+                rotation: RotationTrigger::Interval(u32::max_value()),
+                trade: TradeRules {
+                    timeout_secs: -1.1754942e-38,
+                    max_drops: u32::max_value(),
+                    rings: false,
+                },
+            },
+        }
+    }
+
+    fn frame_len(msg: &ServerMessage) -> u32 {
+        4 + schema::encode(Kind::Message, msg).unwrap().len() as u32
+    }
+
+    #[test]
+    fn the_largest_state_is_valid() {
+        let ps = largest_state();
+        assert!(ClientMessage::State(ps).validate().is_ok());
+    }
+
+    #[test]
+    fn a_state_fits_its_budget() {
+        let one = frame_len(&ServerMessage::Rotate(vec![largest_state()]));
+        let none = frame_len(&ServerMessage::Rotate(vec![]));
+        assert!(one - none <= MAX_STATE_BYTES);
+        assert!(none <= ROTATE_OVERHEAD);
+    }
+
+    #[test]
+    fn a_full_room_rotates_in_one_frame() {
+        for &max_frame in &[min_frame(), DEFAULT_MAX_FRAME, 1024 * 1024] {
+            let n = room_capacity(max_frame);
+            assert!(n >= 2);
+            let states = vec![largest_state(); n];
+            assert!(frame_len(&ServerMessage::Rotate(states)) <= max_frame);
+        }
+    }
+
+    #[test]
+    fn rotations_out_of_range_are_invalid() {
+        let triggers = [RotationTrigger::Score(0), RotationTrigger::Interval(0),
+                        RotationTrigger::Lines(0), RotationTrigger::Pieces(0),
+                        RotationTrigger::Score(u32::max_value())];
+        for &rotation in triggers.iter() {
+            let settings = RoomSettings { rotation: rotation, ..RoomSettings::default() };
+            let msg = ClientMessage::CreateRoomWith("room".to_string(), settings);
+            assert!(msg.validate().is_err(), "{:?} passed", rotation);
+        }
+        let msg = ClientMessage::CreateRoomWith("room".to_string(), RoomSettings::default());
+        assert!(msg.validate().is_ok());
+    }
+
+    #[test]
+    fn a_room_fits_its_budget() {
+        let one = frame_len(&ServerMessage::RoomList(vec![largest_room()]));
+        let none = frame_len(&ServerMessage::RoomList(vec![]));
+        assert!(one - none <= MAX_ROOM_INFO_BYTES);
+        assert!(none <= ROOM_LIST_OVERHEAD);
+    }
+
+    #[test]
+    fn a_full_room_list_fits_in_one_frame() {
+        for &max_frame in &[min_frame(), DEFAULT_MAX_FRAME, 1024 * 1024] {
+            let rooms = vec![largest_room(); room_list_capacity(max_frame)];
+            assert!(frame_len(&ServerMessage::RoomList(rooms)) <= max_frame);
+        }
+    }
+
+    #[test]
+    fn rings_longer_than_the_limit_are_invalid() {
+        let mut ps = largest_state();
+        ps.next_tetromino.2 = TradeState::Ring(vec![0; RING_MAX + 1]);
+        assert!(ClientMessage::State(ps).validate().is_err());
+    }
+}
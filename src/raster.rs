@@ -0,0 +1,328 @@
+use std::cmp::Ordering;
+use std::f32::{INFINITY, NEG_INFINITY};
+use std::f32::consts::PI;
+
+use nalgebra::{Vector3, Point3, Isometry3};
+
+use playerstate::{PlayerState, Shape, Cell, ROWS, COLS, cell_of_shape};
+use theme::{Theme, Color};
+use png::Image;
+
+// the window camera's: looking down +z from just behind the origin
+const FOV: f32 = PI / 4.0;
+const EYE_Z: f32 = -1.0;
+const CUBE_SIZE: f32 = 0.8;
+// how see-through other players' cubes are before the theme fades them
+const PEER_ALPHA: f32 = 0.5;
+
+fn point(v: Vector3<f32>) -> Point3<f32> {
+    Point3::new(v.x, v.y, v.z)
+}
+
+struct Cube {
+    centre: Vector3<f32>,
+    color: Color,
+    alpha: f32,
+}
+
+// one visible face of a cube, ready to fill
+struct Face {
+    corners: Vec<(f32, f32)>,
+    depth: f32,
+    color: Color,
+    alpha: f32,
+}
+
+fn shape_cubes(cubes: &mut Vec<Cube>, shape: Shape, rot: usize, origin: (f32, f32, f32),
+               theme: &Theme, alpha: f32) {
+    let color = theme.color(cell_of_shape(shape));
+    for r in 0..4 {
+        for c in 0..4 {
+            if shape[rot][r][c] != 0 {
+                cubes.push(Cube {
+                    centre: Vector3::new(origin.0 + c as f32, origin.1 + r as f32, origin.2),
+                    color: color,
+                    alpha: alpha,
+                });
+            }
+        }
+    }
+}
+
+// the layered view's cubes, laid out as Draw lays them out: boards front to
+// back from the viewer's, falling pieces, and the next pieces to one side
+fn scene(player_states: &Vec<PlayerState>, my_id: usize, theme: &Theme) -> Vec<Cube> {
+    let n = player_states.len();
+    let my_idx = player_states.iter().position(|ps| ps.id == my_id).unwrap_or(0);
+    let half = (COLS as f32 / 2.0 - 0.5, ROWS as f32 / 2.0 - 0.5);
+    let mut cubes = Vec::new();
+    for (idx, ps) in player_states.iter().enumerate() {
+        let rel = (idx + n - my_idx) % n;
+        let z = -half.0 + rel as f32;
+        let alpha = if rel == 0 { 1.0 } else { PEER_ALPHA * theme.peer_opacity };
+        for r in 0..ROWS - 2 {
+            for c in 0..COLS {
+                if ps.board[r][c] != Cell::E {
+                    cubes.push(Cube {
+                        centre: Vector3::new(c as f32 - half.0, r as f32 - half.1, z),
+                        color: theme.color(ps.board[r][c]),
+                        alpha: alpha,
+                    });
+                }
+            }
+        }
+        shape_cubes(&mut cubes, ps.tetromino.0, ps.tetromino.1,
+                    (ps.tetro_pos.1 as f32 - half.0, ps.tetro_pos.0 as f32 - half.1, z),
+                    theme, alpha);
+        // the carousel at rest: ours level with the board, the rest above
+        // and below it in turn
+        let slot = (rel as isize + n as isize / 2) % n as isize - n as isize / 2;
+        shape_cubes(&mut cubes, ps.next_tetromino.0, ps.next_tetromino.1,
+                    ((-4 - (COLS / 2) as isize) as f32, slot as f32 * 5.0, z),
+                    theme, 1.0);
+    }
+    cubes
+}
+
+// the faces of a cube the camera can see, in screen coordinates
+fn faces(cube: &Cube, view: &Isometry3<f32>, width: f32, height: f32, out: &mut Vec<Face>) {
+    let focal = height / 2.0 / (FOV / 2.0).tan();
+    let h = CUBE_SIZE / 2.0;
+    let axes = [Vector3::new(1.0, 0.0, 0.0), Vector3::new(0.0, 1.0, 0.0),
+                Vector3::new(0.0, 0.0, 1.0)];
+    for a in 0..3 {
+        let (u, v) = (axes[(a + 1) % 3], axes[(a + 2) % 3]);
+        for &sign in [-1.0f32, 1.0].iter() {
+            let normal = axes[a] * sign;
+            let centre = *view * point(cube.centre + normal * h);
+            let n = view.rotation * normal;
+            let to_eye = Vector3::new(-centre.x, -centre.y, EYE_Z - centre.z);
+            let facing = n.x * to_eye.x + n.y * to_eye.y + n.z * to_eye.z;
+            if facing <= 0.0 {
+                continue;
+            }
+            let mut corners = Vec::with_capacity(4);
+            let mut depth = 0.0;
+            for &(du, dv) in [(-1.0f32, -1.0f32), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)].iter() {
+                let p = *view * point(cube.centre + normal * h + u * (du * h) + v * (dv * h));
+                let dz = p.z - EYE_Z;
+                if dz <= 0.01 {
+                    return;
+                }
+                // +x is to the left as the camera sees it
+                corners.push((width / 2.0 - p.x / dz * focal, height / 2.0 - p.y / dz * focal));
+                depth += dz / 4.0;
+            }
+            // lit from the camera, as the window's light sticks to it
+            let len = (to_eye.x * to_eye.x + to_eye.y * to_eye.y + to_eye.z * to_eye.z).sqrt();
+            let light = 0.4 + 0.6 * facing / len;
+            out.push(Face {
+                corners: corners,
+                depth: depth,
+                color: (cube.color.0 * light, cube.color.1 * light, cube.color.2 * light),
+                alpha: cube.alpha,
+            });
+        }
+    }
+}
+
+// fills a convex polygon, testing pixel centres against every edge
+fn fill(image: &mut Image, face: &Face) {
+    let xs = face.corners.iter().map(|p| p.0);
+    let ys = face.corners.iter().map(|p| p.1);
+    let x0 = xs.clone().fold(INFINITY, |a, b| a.min(b)).max(0.0) as usize;
+    let x1 = xs.fold(NEG_INFINITY, |a, b| a.max(b)).min(image.width as f32 - 1.0);
+    let y0 = ys.clone().fold(INFINITY, |a, b| a.min(b)).max(0.0) as usize;
+    let y1 = ys.fold(NEG_INFINITY, |a, b| a.max(b)).min(image.height as f32 - 1.0);
+    if x1 < 0.0 || y1 < 0.0 {
+        return;
+    }
+    let k = face.corners.len();
+    for y in y0..y1 as usize + 1 {
+        for x in x0..x1 as usize + 1 {
+            let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+            let mut pos = false;
+            let mut neg = false;
+            for i in 0..k {
+                let (ax, ay) = face.corners[i];
+                let (bx, by) = face.corners[(i + 1) % k];
+                let side = (bx - ax) * (py - ay) - (by - ay) * (px - ax);
+                pos |= side > 0.0;
+                neg |= side < 0.0;
+            }
+            if !(pos && neg) {
+                image.blend(x, y, face.color, face.alpha);
+            }
+        }
+    }
+}
+
+// draws the layered boards without a window, for screenshots and for
+// checking layouts on machines with no display
+pub fn render(player_states: &Vec<PlayerState>, my_id: usize, theme: &Theme,
+              view: &Isometry3<f32>, width: usize, height: usize) -> Image {
+    let bg = theme.background;
+    let mut image = Image::new(width, height, ((bg.0 * 255.0) as u8, (bg.1 * 255.0) as u8,
+                                               (bg.2 * 255.0) as u8));
+    let mut visible = Vec::new();
+    for cube in scene(player_states, my_id, theme).iter() {
+        faces(cube, view, width as f32, height as f32, &mut visible);
+    }
+    // far to near, so nearer faces paint over and blend onto farther ones
+    visible.sort_by(|a, b| b.depth.partial_cmp(&a.depth).unwrap_or(Ordering::Equal));
+    for face in visible.iter() {
+        fill(&mut image, face);
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::File;
+    use std::io::prelude::*;
+    use std::path::PathBuf;
+    use std::f32::consts::PI;
+
+    use nalgebra::Isometry3;
+    use num::traits::One;
+
+    use super::render;
+    use camera::CameraRig;
+    use playerstate::*;
+    use theme::Theme;
+    use png::Image;
+
+    // small, the files are stored uncompressed
+    const SIZE: (usize, usize) = (128, 96);
+
+    fn golden_path(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden")
+            .join(format!("{}.png", name))
+    }
+
+    // a board partway through a game, the same every time
+    fn board(id: usize, shape: Shape, next: Shape) -> PlayerState {
+        let mut ps = PlayerState::new(id);
+        let cells = [Cell::I, Cell::J, Cell::L, Cell::O, Cell::S, Cell::T, Cell::Z];
+        for r in 0..4 + id {
+            for c in 0..COLS {
+                if (r * 3 + c + id) % 5 != 0 {
+                    ps.board[r][c] = cells[(r + c * 2 + id) % cells.len()];
+                }
+            }
+        }
+        ps.tetromino = (shape, 1);
+        ps.tetro_pos = (12, 3);
+        ps.next_tetromino = (next, 0, TradeState::NoTrade);
+        ps
+    }
+
+    fn front() -> Isometry3<f32> {
+        CameraRig::new().transform(&Isometry3::one())
+    }
+
+    fn isometric() -> Isometry3<f32> {
+        let mut rig = CameraRig::new();
+        rig.yaw = PI / 4.0;
+        rig.pitch = -0.6;
+        rig.distance = 36.0;
+        rig.transform(&Isometry3::one())
+    }
+
+    // the PNGs written by encode_png: one stored IDAT, no filtering
+    fn decode(bytes: &[u8]) -> Image {
+        let be = |b: &[u8]| ((b[0] as usize) << 24) | ((b[1] as usize) << 16) |
+                            ((b[2] as usize) << 8) | b[3] as usize;
+        let (mut width, mut height, mut idat) = (0, 0, Vec::new());
+        let mut at = 8;
+        while at < bytes.len() {
+            let len = be(&bytes[at..]);
+            let data = &bytes[at + 8..at + 8 + len];
+            match &bytes[at + 4..at + 8] {
+                b"IHDR" => {
+                    width = be(data);
+                    height = be(&data[4..]);
+                },
+                b"IDAT" => idat.extend_from_slice(data),
+                _ => (),
+            }
+            at += 12 + len;
+        }
+        let mut raw = Vec::new();
+        let mut at = 2;
+        loop {
+            let last = idat[at] & 1 == 1;
+            let len = idat[at + 1] as usize | (idat[at + 2] as usize) << 8;
+            raw.extend_from_slice(&idat[at + 5..at + 5 + len]);
+            at += 5 + len;
+            if last {
+                break;
+            }
+        }
+        let mut pixels = Vec::new();
+        for row in raw.chunks(width * 3 + 1) {
+            pixels.extend_from_slice(&row[1..]);
+        }
+        Image {
+            width: width,
+            height: height,
+            pixels: pixels,
+        }
+    }
+
+    // compares with the checked-in image, leaving some room for pixels on
+    // the edge of a face that land on the other side with other float
+    // maths. UPDATE_GOLDEN=1 writes the images instead.
+    fn check(name: &str, image: Image) {
+        let path = golden_path(name);
+        if env::var("UPDATE_GOLDEN").is_ok() {
+            image.save_png(&path).unwrap();
+            return;
+        }
+        let mut bytes = Vec::new();
+        File::open(&path).and_then(|mut f| f.read_to_end(&mut bytes))
+            .expect("missing golden image, run with UPDATE_GOLDEN=1");
+        let golden = decode(&bytes);
+        assert_eq!((golden.width, golden.height), (image.width, image.height));
+        let differing = golden.pixels.chunks(3).zip(image.pixels.chunks(3))
+            .filter(|&(a, b)| a.iter().zip(b.iter()).any(|(&x, &y)| (x as i32 - y as i32).abs() > 2))
+            .count();
+        if differing > image.width {
+            let actual = env::temp_dir().join(format!("{}.png", name));
+            image.save_png(&actual).unwrap();
+            panic!("{} pixels differ from {:?}, this render is at {:?}",
+                   differing, path, actual);
+        }
+    }
+
+    #[test]
+    fn decode_reads_back_encode() {
+        let mut image = Image::new(3, 2, (10, 20, 30));
+        image.blend(1, 1, (1.0, 0.5, 0.0), 1.0);
+        let back = decode(&image.encode_png());
+        assert_eq!((back.width, back.height), (3, 2));
+        assert_eq!(back.pixels, image.pixels);
+    }
+
+    #[test]
+    fn one_board_from_the_front() {
+        let states = vec![board(0, TSHAPE, LSHAPE)];
+        check("front", render(&states, 0, &Theme::classic(), &front(), SIZE.0, SIZE.1));
+    }
+
+    #[test]
+    fn three_boards_from_above() {
+        let states = vec![board(0, ISHAPE, OSHAPE), board(1, SSHAPE, ZSHAPE),
+                          board(2, JSHAPE, TSHAPE)];
+        check("isometric", render(&states, 1, &Theme::classic(), &isometric(),
+                                  SIZE.0, SIZE.1));
+    }
+
+    #[test]
+    fn another_theme() {
+        let states = vec![board(0, ZSHAPE, ISHAPE), board(1, OSHAPE, SSHAPE)];
+        check("deuteranopia", render(&states, 0, &Theme::deuteranopia(), &isometric(),
+                                     SIZE.0, SIZE.1));
+    }
+}
@@ -0,0 +1,70 @@
+use std::path::Path;
+
+use serde_json::Value;
+
+use playerstate::PlayerState;
+use tradelog::TradeRecord;
+use schema::{Versioned, SchemaError, Kind, write_file, read_file, from_payload};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplayFrame {
+    pub tick: u32,
+    pub states: Vec<PlayerState>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Replay {
+    pub room: usize,
+    pub frames: Vec<ReplayFrame>,
+    #[serde(default)]
+    pub trades: Vec<TradeRecord>,
+}
+
+impl Versioned for Replay {
+    // version 1 could record TradeState::Confirm, which no longer exists. A
+    // trade in that state hadn't happened yet, so it reads as no trade.
+    fn upgrade(version: u16, mut payload: Value) -> Result<Replay, SchemaError> {
+        if version != 1 {
+            return Err(SchemaError::UnsupportedVersion(version));
+        }
+        if let Some(frames) = payload.get_mut("frames").and_then(|f| f.as_array_mut()) {
+            for frame in frames.iter_mut() {
+                if let Some(states) = frame.get_mut("states").and_then(|s| s.as_array_mut()) {
+                    for ps in states.iter_mut() {
+                        if let Some(trade) = ps.pointer_mut("/next_tetromino/2") {
+                            if trade.get("Confirm").is_some() {
+                                *trade = Value::String("NoTrade".to_string());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        from_payload(payload)
+    }
+}
+
+impl Replay {
+    pub fn new(room: usize) -> Replay {
+        Replay {
+            room: room,
+            frames: Vec::new(),
+            trades: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, tick: u32, states: &Vec<PlayerState>) {
+        self.frames.push(ReplayFrame {
+            tick: tick,
+            states: states.clone(),
+        });
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), SchemaError> {
+        write_file(path, Kind::Replay, self)
+    }
+
+    pub fn load(path: &Path) -> Result<Replay, SchemaError> {
+        read_file(path, Kind::Replay)
+    }
+}
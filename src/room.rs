@@ -0,0 +1,863 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use protocol::{ClientMessage, ServerMessage, RoomInfo, RoomSettings, TradeEnd, room_capacity,
+               room_list_capacity, ROOM_NAME_MAX_LEN};
+use chat::CHAT_MAX_LEN;
+use playerstate::{PlayerState, TradeState, BoardState};
+use netstats::{LinkStats, PING_INTERVAL_MS};
+use boardmap::{ColumnMap, Board};
+use trade::{TradeBook, Offer, OfferResult, Ring, RingResult, RING_MIN, RING_MAX};
+
+pub struct Room {
+    pub id: usize,
+    pub name: String,
+    pub settings: RoomSettings,
+    // player id -> connection id, vacated slots are reused by the next joiner
+    slots: Vec<Option<usize>>,
+    // connection ids that receive broadcasts without owning a slot
+    spectators: Vec<usize>,
+    // latest relayed state per player id
+    states: Vec<Option<PlayerState>>,
+    pub trades: TradeBook,
+    // a rotation has been ordered and not everyone is stable again
+    rotating: bool,
+}
+
+impl Room {
+    pub fn new(id: usize, name: String, settings: RoomSettings) -> Room {
+        Room {
+            id: id,
+            name: name,
+            settings: settings,
+            slots: Vec::new(),
+            spectators: Vec::new(),
+            states: Vec::new(),
+            trades: TradeBook::new(),
+            rotating: false,
+        }
+    }
+
+    pub fn join(&mut self, conn: usize) -> usize {
+        match self.slots.iter().position(|s| s.is_none()) {
+            Some(pid) => {
+                self.slots[pid] = Some(conn);
+                pid
+            },
+            None => {
+                self.slots.push(Some(conn));
+                self.slots.len() - 1
+            },
+        }
+    }
+
+    pub fn spectate(&mut self, conn: usize) {
+        self.spectators.push(conn);
+    }
+
+    pub fn leave(&mut self, conn: usize) -> Option<usize> {
+        self.spectators.retain(|&s| s != conn);
+        let pid = self.player_id(conn);
+        if let Some(pid) = pid {
+            self.slots[pid] = None;
+            if pid < self.states.len() {
+                self.states[pid] = None;
+            }
+        }
+        pid
+    }
+
+    pub fn conn_of(&self, pid: usize) -> Option<usize> {
+        if pid < self.slots.len() { self.slots[pid] } else { None }
+    }
+
+    pub fn state_of(&self, pid: usize) -> Option<&PlayerState> {
+        match self.states.get(pid) {
+            Some(&Some(ref ps)) => Some(ps),
+            _ => None,
+        }
+    }
+
+    // every player's state with their board after the rotation, once every
+    // player is paused and ready, at most once per rotation
+    pub fn rotation_due(&mut self) -> Option<Vec<PlayerState>> {
+        let mut ready = Vec::new();
+        let mut all_stable = true;
+        for (pid, slot) in self.slots.iter().enumerate() {
+            if slot.is_none() {
+                continue;
+            }
+            match self.state_of(pid) {
+                Some(ps) => {
+                    all_stable &= ps.board_state == BoardState::Stable;
+                    if ps.board_state == BoardState::Ready {
+                        ready.push(ps.clone());
+                    }
+                },
+                None => return None,
+            }
+        }
+        if self.rotating {
+            self.rotating = !all_stable;
+            return None;
+        }
+        if ready.is_empty() || ready.len() < self.players().len() {
+            return None;
+        }
+        self.rotating = true;
+
+        let map = ColumnMap::quarter_turn(ready.len());
+        let boards: Vec<Board> = ready.iter().map(|ps| ps.board).collect();
+        let rotated = map.apply(&boards);
+        debug_assert!(map.inverse().apply(&rotated) == boards);
+        for (ps, board) in ready.iter_mut().zip(rotated.into_iter()) {
+            ps.board = board;
+        }
+        Some(ready)
+    }
+
+    // a Ready state sent before the player saw the rotation it was ready
+    // for, relaying it would put the board from before the swap back
+    pub fn is_stale(&self, ps: &PlayerState) -> bool {
+        self.rotating && ps.board_state == BoardState::Ready
+    }
+
+    // each player's piece count as last heard, by player id
+    pub fn pieces(&self) -> Vec<Option<u32>> {
+        self.states.iter().map(|s| s.as_ref().map(|ps| ps.pieces)).collect()
+    }
+
+    pub fn set_state(&mut self, ps: PlayerState) {
+        let pid = ps.id;
+        while self.states.len() <= pid {
+            self.states.push(None);
+        }
+        self.states[pid] = Some(ps);
+    }
+
+    pub fn has_player(&self, pid: usize) -> bool {
+        pid < self.slots.len() && self.slots[pid].is_some()
+    }
+
+    pub fn player_id(&self, conn: usize) -> Option<usize> {
+        self.slots.iter().position(|s| *s == Some(conn))
+    }
+
+    pub fn players(&self) -> Vec<usize> {
+        self.slots.iter().filter_map(|s| *s).collect()
+    }
+
+    pub fn connections(&self) -> Vec<usize> {
+        let mut conns = self.players();
+        conns.extend(self.spectators.iter().cloned());
+        conns
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.iter().all(|s| s.is_none()) && self.spectators.is_empty()
+    }
+
+    pub fn info(&self) -> RoomInfo {
+        RoomInfo {
+            id: self.id,
+            name: self.name.clone(),
+            players: self.players().len(),
+            spectators: self.spectators.len(),
+            settings: self.settings,
+        }
+    }
+}
+
+// server-side game bookkeeping, the network loop feeds it messages and
+// delivers whatever it queues in the outbox
+pub struct Lobby {
+    outbox: Vec<(usize, ServerMessage)>,
+    kicked: Vec<usize>,
+    rooms: HashMap<usize, Room>,
+    // connection id -> room id
+    membership: HashMap<usize, usize>,
+    stats: HashMap<usize, LinkStats>,
+    next_room_id: usize,
+    last_ping: Instant,
+    max_frame: u32,
+}
+
+impl Lobby {
+    pub fn new(max_frame: u32) -> Lobby {
+        Lobby {
+            outbox: Vec::new(),
+            kicked: Vec::new(),
+            rooms: HashMap::new(),
+            membership: HashMap::new(),
+            stats: HashMap::new(),
+            next_room_id: 0,
+            last_ping: Instant::now(),
+            max_frame: max_frame,
+        }
+    }
+
+    pub fn connect(&mut self, conn: usize) {
+        self.stats.insert(conn, LinkStats::new());
+        let max_frame = self.max_frame;
+        self.send(conn, ServerMessage::Welcome(max_frame));
+    }
+
+    pub fn disconnect(&mut self, conn: usize) {
+        self.leave(conn);
+        self.stats.remove(&conn);
+    }
+
+    pub fn drain_outbox(&mut self) -> Vec<(usize, ServerMessage)> {
+        self.outbox.drain(..).collect()
+    }
+
+    pub fn drain_kicked(&mut self) -> Vec<usize> {
+        self.kicked.drain(..).collect()
+    }
+
+    // tells a misbehaving client why, the network loop drops it after
+    // flushing the message
+    fn kick(&mut self, conn: usize, reason: String) {
+        println!("Kicking client {}: {}", conn, reason);
+        self.send(conn, ServerMessage::Error(reason));
+        self.leave(conn);
+        self.kicked.push(conn);
+    }
+
+    // pings every connection and reports link health to each room
+    pub fn tick(&mut self) {
+        let room_ids: Vec<usize> = self.rooms.keys().cloned().collect();
+        for room_id in room_ids {
+            let timeout = self.rooms[&room_id].settings.trade.timeout_secs;
+            let expired = self.rooms.get_mut(&room_id).unwrap().trades.expire(timeout);
+            self.close_offers(room_id, expired, TradeEnd::Expired);
+            let expired = self.rooms.get_mut(&room_id).unwrap().trades.expire_rings(timeout);
+            self.close_rings(room_id, expired, TradeEnd::Expired);
+        }
+
+        if self.last_ping.elapsed() < Duration::from_millis(PING_INTERVAL_MS) {
+            return;
+        }
+        self.last_ping = Instant::now();
+
+        let conns: Vec<usize> = self.stats.keys().cloned().collect();
+        for conn in conns {
+            let seq = self.stats.get_mut(&conn).unwrap().ping();
+            self.send(conn, ServerMessage::Ping(seq));
+        }
+
+        let room_ids: Vec<usize> = self.rooms.keys().cloned().collect();
+        for room_id in room_ids {
+            let players = self.rooms[&room_id].players();
+            for conn in players {
+                let pid = self.rooms[&room_id].player_id(conn).unwrap();
+                let report = self.stats[&conn].report();
+                self.broadcast(room_id, None, ServerMessage::PeerStatus(pid, report));
+            }
+        }
+    }
+
+    pub fn handle(&mut self, conn: usize, msg: ClientMessage) {
+        if self.kicked.contains(&conn) {
+            return;
+        }
+        if let Some(stats) = self.stats.get_mut(&conn) {
+            stats.seen();
+        }
+        match msg {
+            ClientMessage::ListRooms => {
+                let mut list: Vec<RoomInfo> =
+                    self.rooms.values().map(|r| r.info()).collect();
+                list.sort_by_key(|r| r.id);
+                // the oldest rooms, as many as fit in a frame; the rest can
+                // still be joined by id
+                list.truncate(room_list_capacity(self.max_frame));
+                self.send(conn, ServerMessage::RoomList(list));
+            },
+            ClientMessage::CreateRoom(name) =>
+                self.create_room(conn, name, RoomSettings::default()),
+            ClientMessage::CreateRoomWith(name, settings) =>
+                self.create_room(conn, name, settings),
+            ClientMessage::JoinRoom(room_id) => {
+                let full = self.rooms.get(&room_id)
+                    .map(|room| room.players().len() >= room_capacity(self.max_frame));
+                if full == Some(true) {
+                    self.send(conn, ServerMessage::Error(
+                        format!("Room {} is full", room_id)));
+                }
+                else if full == Some(false) {
+                    self.leave(conn);
+                    self.join(conn, room_id);
+                }
+                else {
+                    self.send(conn, ServerMessage::Error(
+                        format!("No room with id {}", room_id)));
+                }
+            },
+            ClientMessage::SpectateRoom(room_id) => {
+                if self.rooms.contains_key(&room_id) {
+                    self.leave(conn);
+                    self.rooms.get_mut(&room_id).unwrap().spectate(conn);
+                    self.membership.insert(conn, room_id);
+                    self.send(conn, ServerMessage::Spectating(room_id));
+                    let settings = self.rooms[&room_id].settings;
+                    self.send(conn, ServerMessage::Settings(settings));
+                }
+                else {
+                    self.send(conn, ServerMessage::Error(
+                        format!("No room with id {}", room_id)));
+                }
+            },
+            ClientMessage::LeaveRoom => {
+                self.leave(conn);
+                self.send(conn, ServerMessage::Left);
+            },
+            ClientMessage::State(ps) => {
+                if let Some(&room_id) = self.membership.get(&conn) {
+                    // only relay states for the slot this connection owns,
+                    // spectators own none
+                    if self.rooms[&room_id].player_id(conn) != Some(ps.id)
+                        || self.rooms[&room_id].is_stale(&ps) {
+                        return;
+                    }
+                    // the partner may have left since this state was sent
+                    if let TradeState::Pending(t) = ps.next_tetromino.2 {
+                        if !self.rooms[&room_id].has_player(t) {
+                            self.send(conn, ServerMessage::TradeClosed(t, TradeEnd::PartnerLeft));
+                        }
+                    }
+                    self.rooms.get_mut(&room_id).unwrap().set_state(ps.clone());
+                    self.broadcast(room_id, Some(conn), ServerMessage::State(ps));
+                    if let Some(snapshot) = self.rooms.get_mut(&room_id).unwrap().rotation_due() {
+                        self.broadcast(room_id, None, ServerMessage::Rotate(snapshot));
+                    }
+                }
+            },
+            ClientMessage::Chat(text) => {
+                if let Some(&room_id) = self.membership.get(&conn) {
+                    let from = self.rooms[&room_id].player_id(conn);
+                    let text: String = text.chars().take(CHAT_MAX_LEN).collect();
+                    self.broadcast(room_id, None, ServerMessage::Chat(from, text));
+                }
+            },
+            ClientMessage::Pong(seq) => {
+                if let Some(stats) = self.stats.get_mut(&conn) {
+                    stats.pong(seq);
+                }
+            },
+            ClientMessage::TradeOffer(target, seq) => {
+                if let Some((room_id, pid)) = self.player_of(conn) {
+                    if target == pid {
+                        return self.kick(conn, "trade with yourself".to_string());
+                    }
+                    if !self.rooms[&room_id].has_player(target) {
+                        self.rooms.get_mut(&room_id).unwrap().trades.withdraw(pid);
+                        return self.send(conn,
+                                         ServerMessage::TradeClosed(target, TradeEnd::PartnerLeft));
+                    }
+                    // an offer is for the next piece its owner last told us
+                    // about, with the shape we know it to have
+                    let shape = match self.rooms[&room_id].state_of(pid) {
+                        Some(ps) if ps.pieces == seq => ps.next_tetromino.0,
+                        _ => {
+                            self.rooms.get_mut(&room_id).unwrap().trades.withdraw(pid);
+                            return self.send(conn,
+                                             ServerMessage::TradeClosed(target, TradeEnd::Rejected));
+                        },
+                    };
+                    let left = self.rooms.get_mut(&room_id).unwrap().trades.leave_rings(pid, None);
+                    self.close_rings(room_id, left, TradeEnd::PartnerLeft);
+                    let to_pieces = self.rooms[&room_id].state_of(target).map(|ps| ps.pieces);
+                    let max_drops = self.rooms[&room_id].settings.trade.max_drops;
+                    let result = self.rooms.get_mut(&room_id).unwrap()
+                        .trades.offer(pid, target, seq, shape, to_pieces, max_drops);
+                    match result {
+                        // both sides hear of the trade in the same message, so
+                        // neither can end up with or without both pieces
+                        OfferResult::Committed(commit) =>
+                            self.broadcast(room_id, None, ServerMessage::TradeCommitted(commit)),
+                        OfferResult::Stale(other) => {
+                            self.send(conn, ServerMessage::TradeClosed(target, TradeEnd::Rejected));
+                            self.close_offers(room_id, vec![other], TradeEnd::Rejected);
+                        },
+                        OfferResult::Expired =>
+                            self.send(conn, ServerMessage::TradeClosed(target, TradeEnd::Expired)),
+                        OfferResult::Open => (),
+                    }
+                }
+            },
+            ClientMessage::TradeWithdraw => {
+                if let Some((room_id, pid)) = self.player_of(conn) {
+                    self.rooms.get_mut(&room_id).unwrap().trades.withdraw(pid);
+                }
+            },
+            ClientMessage::RingPropose(members, seq) => {
+                if let Some((room_id, pid)) = self.player_of(conn) {
+                    let mut distinct = members.clone();
+                    distinct.sort();
+                    distinct.dedup();
+                    let valid = members.len() >= RING_MIN && members.len() <= RING_MAX
+                        && distinct.len() == members.len() && members[0] == pid;
+                    if !valid {
+                        return self.kick(conn, format!("bad trade ring {:?}", members));
+                    }
+                    // as with offers, the proposer's piece is the next one
+                    // we last heard of, with the shape we know it to have
+                    let (shape, refused) = {
+                        let room = &self.rooms[&room_id];
+                        let shape = match room.state_of(pid) {
+                            Some(ps) if ps.pieces == seq => Some(ps.next_tetromino.0),
+                            _ => None,
+                        };
+                        let refused =
+                            if !room.settings.trade.rings || shape.is_none() {
+                                Some(TradeEnd::Rejected)
+                            }
+                            // someone may have left since the proposer last heard
+                            else if !members.iter().all(|&m| room.has_player(m)) {
+                                Some(TradeEnd::PartnerLeft)
+                            }
+                            else { None };
+                        (shape, refused)
+                    };
+                    if let Some(why) = refused {
+                        return self.refuse_ring(room_id, conn, members, why);
+                    }
+                    self.release(room_id, pid, None);
+                    let id = self.rooms.get_mut(&room_id).unwrap()
+                        .trades.propose_ring(members.clone(), seq, shape.unwrap());
+                    for &m in members.iter() {
+                        if let Some(c) = self.rooms[&room_id].conn_of(m) {
+                            self.send(c, ServerMessage::RingProposed(id, members.clone()));
+                        }
+                    }
+                }
+            },
+            ClientMessage::RingJoin(id, seq) => {
+                if let Some((room_id, pid)) = self.player_of(conn) {
+                    let shape = match self.rooms[&room_id].state_of(pid) {
+                        Some(ps) if ps.pieces == seq => Some(ps.next_tetromino.0),
+                        _ => None,
+                    };
+                    let shape = match shape {
+                        Some(shape) => shape,
+                        None => {
+                            // the piece has moved on, the ring can't have it
+                            let is_member = match self.rooms[&room_id].trades.ring(id) {
+                                Some(ring) => ring.members.contains(&pid),
+                                None => false,
+                            };
+                            if is_member {
+                                let stale = self.rooms.get_mut(&room_id).unwrap()
+                                    .trades.close_ring(id).into_iter().collect();
+                                self.close_rings(room_id, stale, TradeEnd::Rejected);
+                            }
+                            return;
+                        },
+                    };
+                    self.release(room_id, pid, Some(id));
+                    let result = {
+                        let room = self.rooms.get_mut(&room_id).unwrap();
+                        let pieces = room.pieces();
+                        let max_drops = room.settings.trade.max_drops;
+                        room.trades.join_ring(id, pid, seq, shape, &pieces, max_drops)
+                    };
+                    match result {
+                        RingResult::Committed(commit) =>
+                            self.broadcast(room_id, None, ServerMessage::RingCommitted(commit)),
+                        RingResult::Stale(ring) =>
+                            self.close_rings(room_id, vec![ring], TradeEnd::Rejected),
+                        RingResult::Expired(ring) =>
+                            self.close_rings(room_id, vec![ring], TradeEnd::Expired),
+                        RingResult::Open => (),
+                    }
+                }
+            },
+            ClientMessage::RingLeave(id) => {
+                if let Some((room_id, pid)) = self.player_of(conn) {
+                    let is_member = match self.rooms[&room_id].trades.ring(id) {
+                        Some(ring) => ring.members.contains(&pid),
+                        None => false,
+                    };
+                    if is_member {
+                        let left = self.rooms.get_mut(&room_id).unwrap()
+                            .trades.close_ring(id).into_iter().collect();
+                        self.close_rings(room_id, left, TradeEnd::Declined);
+                    }
+                }
+            },
+            ClientMessage::TradeDecline(from) => {
+                if let Some((room_id, pid)) = self.player_of(conn) {
+                    let offer = self.rooms.get_mut(&room_id).unwrap().trades.decline(from, pid);
+                    if let Some(offer) = offer {
+                        self.close_offers(room_id, vec![offer], TradeEnd::Declined);
+                    }
+                }
+            },
+        }
+    }
+
+    fn player_of(&self, conn: usize) -> Option<(usize, usize)> {
+        let room_id = match self.membership.get(&conn) {
+            Some(&room_id) => room_id,
+            None => return None,
+        };
+        self.rooms[&room_id].player_id(conn).map(|pid| (room_id, pid))
+    }
+
+    // frees a player to join a ring, withdrawing their pairwise offer and
+    // closing any other ring they were part of
+    fn release(&mut self, room_id: usize, pid: usize, keep: Option<usize>) {
+        let left = {
+            let trades = &mut self.rooms.get_mut(&room_id).unwrap().trades;
+            trades.withdraw(pid);
+            trades.leave_rings(pid, keep)
+        };
+        self.close_rings(room_id, left, TradeEnd::PartnerLeft);
+    }
+
+    // numbers a ring that never opened and tells its proposer it's off
+    fn refuse_ring(&mut self, room_id: usize, conn: usize, members: Vec<usize>, why: TradeEnd) {
+        let id = self.rooms.get_mut(&room_id).unwrap().trades.number_ring();
+        self.send(conn, ServerMessage::RingProposed(id, members));
+        self.send(conn, ServerMessage::RingClosed(id, why));
+    }
+
+    // tells every member of each ring that it's off
+    fn close_rings(&mut self, room_id: usize, rings: Vec<Ring>, why: TradeEnd) {
+        for ring in rings {
+            for &m in ring.members.iter() {
+                if let Some(conn) = self.rooms[&room_id].conn_of(m) {
+                    self.send(conn, ServerMessage::RingClosed(ring.id, why));
+                }
+            }
+        }
+    }
+
+    // tells each offerer their offer is gone
+    fn close_offers(&mut self, room_id: usize, offers: Vec<Offer>, why: TradeEnd) {
+        for offer in offers {
+            if let Some(conn) = self.rooms[&room_id].conn_of(offer.from) {
+                self.send(conn, ServerMessage::TradeClosed(offer.to, why));
+            }
+        }
+    }
+
+    fn create_room(&mut self, conn: usize, name: String, settings: RoomSettings) {
+        if name.chars().count() > ROOM_NAME_MAX_LEN {
+            return self.send(conn, ServerMessage::Error(
+                format!("Room names are at most {} characters", ROOM_NAME_MAX_LEN)));
+        }
+        self.leave(conn);
+        let room_id = self.next_room_id;
+        self.next_room_id += 1;
+        self.rooms.insert(room_id, Room::new(room_id, name, settings));
+        self.join(conn, room_id);
+    }
+
+    fn join(&mut self, conn: usize, room_id: usize) {
+        let pid = self.rooms.get_mut(&room_id).unwrap().join(conn);
+        self.membership.insert(conn, room_id);
+        self.send(conn, ServerMessage::Joined(room_id, pid));
+        let settings = self.rooms[&room_id].settings;
+        self.send(conn, ServerMessage::Settings(settings));
+    }
+
+    fn leave(&mut self, conn: usize) {
+        if let Some(room_id) = self.membership.remove(&conn) {
+            let (pid, empty) = {
+                let room = self.rooms.get_mut(&room_id).unwrap();
+                (room.leave(conn), room.is_empty())
+            };
+            if empty {
+                self.rooms.remove(&room_id);
+            }
+            else if let Some(pid) = pid {
+                let orphaned = self.rooms.get_mut(&room_id).unwrap().trades.remove_player(pid);
+                self.close_offers(room_id, orphaned, TradeEnd::PartnerLeft);
+                let left = self.rooms.get_mut(&room_id).unwrap().trades.leave_rings(pid, None);
+                self.close_rings(room_id, left, TradeEnd::PartnerLeft);
+                self.broadcast(room_id, None, ServerMessage::PlayerLeft(pid));
+            }
+        }
+    }
+
+    fn send(&mut self, conn: usize, msg: ServerMessage) {
+        self.outbox.push((conn, msg));
+    }
+
+    fn broadcast(&mut self, room_id: usize, except: Option<usize>, msg: ServerMessage) {
+        let targets = match self.rooms.get(&room_id) {
+            Some(room) => room.connections(),
+            None => return,
+        };
+        for conn in targets {
+            if Some(conn) != except {
+                self.outbox.push((conn, msg.clone()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::min_frame;
+    use schema;
+    use schema::Kind;
+    use networkadapter::DEFAULT_MAX_FRAME;
+    use trade::TradeRules;
+
+    // what the lobby sent each connection since last asked, in order
+    fn sent(lobby: &mut Lobby, conn: usize) -> Vec<ServerMessage> {
+        lobby.drain_outbox().into_iter()
+            .filter(|&(c, _)| c == conn)
+            .map(|(_, msg)| msg)
+            .collect()
+    }
+
+    // connections 0..players, all in room 0, with nothing left to deliver
+    fn room_of(lobby: &mut Lobby, players: usize, settings: RoomSettings) {
+        for conn in 0..players {
+            lobby.connect(conn);
+            if conn == 0 {
+                lobby.handle(conn, ClientMessage::CreateRoomWith("room".to_string(), settings));
+            }
+            else {
+                lobby.handle(conn, ClientMessage::JoinRoom(0));
+            }
+        }
+        lobby.drain_outbox();
+    }
+
+    fn state(pid: usize, pieces: u32) -> PlayerState {
+        let mut ps = PlayerState::new(pid);
+        ps.pieces = pieces;
+        ps
+    }
+
+    fn expect_error(msgs: Vec<ServerMessage>) {
+        match msgs.as_slice() {
+            [ServerMessage::Error(_)] => (),
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn a_new_connection_is_welcomed() {
+        let mut lobby = Lobby::new(DEFAULT_MAX_FRAME);
+        lobby.connect(3);
+        match sent(&mut lobby, 3).as_slice() {
+            [ServerMessage::Welcome(max_frame)] => assert_eq!(*max_frame, DEFAULT_MAX_FRAME),
+            other => panic!("expected a welcome, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn joiners_take_the_next_free_slot() {
+        let mut lobby = Lobby::new(DEFAULT_MAX_FRAME);
+        room_of(&mut lobby, 2, RoomSettings::default());
+        lobby.connect(2);
+        lobby.handle(2, ClientMessage::JoinRoom(0));
+        match sent(&mut lobby, 2).as_slice() {
+            [ServerMessage::Welcome(_), ServerMessage::Joined(0, 2),
+             ServerMessage::Settings(_)] => (),
+            other => panic!("expected to join as player 2, got {:?}", other),
+        }
+        // a vacated slot goes to the next joiner
+        lobby.handle(1, ClientMessage::LeaveRoom);
+        lobby.connect(3);
+        lobby.handle(3, ClientMessage::JoinRoom(0));
+        match sent(&mut lobby, 3).as_slice() {
+            [ServerMessage::Welcome(_), ServerMessage::Joined(0, 1),
+             ServerMessage::Settings(_)] => (),
+            other => panic!("expected to join as player 1, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn leaving_tells_the_room() {
+        let mut lobby = Lobby::new(DEFAULT_MAX_FRAME);
+        room_of(&mut lobby, 2, RoomSettings::default());
+        lobby.handle(1, ClientMessage::LeaveRoom);
+        let out = lobby.drain_outbox();
+        match out.as_slice() {
+            [(0, ServerMessage::PlayerLeft(1)), (1, ServerMessage::Left)] => (),
+            other => panic!("expected the room to hear player 1 left, got {:?}", other),
+        }
+        // and the last one out closes it
+        lobby.handle(0, ClientMessage::LeaveRoom);
+        lobby.handle(0, ClientMessage::ListRooms);
+        match sent(&mut lobby, 0).as_slice() {
+            [ServerMessage::Left, ServerMessage::RoomList(rooms)] => assert!(rooms.is_empty()),
+            other => panic!("expected no rooms, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn states_are_relayed_to_everyone_else() {
+        let mut lobby = Lobby::new(DEFAULT_MAX_FRAME);
+        room_of(&mut lobby, 2, RoomSettings::default());
+        lobby.connect(2);
+        lobby.handle(2, ClientMessage::SpectateRoom(0));
+        lobby.drain_outbox();
+        lobby.handle(0, ClientMessage::State(state(0, 4)));
+        let out = lobby.drain_outbox();
+        let mut to: Vec<usize> = out.iter().map(|&(c, _)| c).collect();
+        to.sort();
+        assert_eq!(to, vec![1, 2]);
+        for &(_, ref msg) in out.iter() {
+            match *msg {
+                ServerMessage::State(ref ps) => assert_eq!((ps.id, ps.pieces), (0, 4)),
+                ref other => panic!("expected a state, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn states_for_someone_elses_slot_are_dropped() {
+        let mut lobby = Lobby::new(DEFAULT_MAX_FRAME);
+        room_of(&mut lobby, 2, RoomSettings::default());
+        lobby.handle(0, ClientMessage::State(state(1, 4)));
+        assert!(lobby.drain_outbox().is_empty());
+        assert!(lobby.drain_kicked().is_empty());
+    }
+
+    #[test]
+    fn ready_states_from_before_a_rotation_are_dropped() {
+        let mut lobby = Lobby::new(DEFAULT_MAX_FRAME);
+        room_of(&mut lobby, 2, RoomSettings::default());
+        let mut ready = vec![state(0, 4), state(1, 4)];
+        for ps in ready.iter_mut() {
+            ps.board_state = BoardState::Ready;
+        }
+        lobby.handle(0, ClientMessage::State(ready[0].clone()));
+        lobby.handle(1, ClientMessage::State(ready[1].clone()));
+        let rotations = lobby.drain_outbox().into_iter()
+            .filter(|&(_, ref msg)| match *msg { ServerMessage::Rotate(_) => true, _ => false })
+            .count();
+        assert_eq!(rotations, 2);
+        // sent before player 0 saw the rotation
+        lobby.handle(0, ClientMessage::State(ready[0].clone()));
+        assert!(lobby.drain_outbox().is_empty());
+        // once it has, its states flow again
+        lobby.handle(0, ClientMessage::State(state(0, 5)));
+        assert_eq!(sent(&mut lobby, 1).len(), 1);
+    }
+
+    #[test]
+    fn a_full_room_turns_joiners_away() {
+        let mut lobby = Lobby::new(min_frame());
+        room_of(&mut lobby, room_capacity(min_frame()), RoomSettings::default());
+        lobby.connect(9);
+        lobby.drain_outbox();
+        lobby.handle(9, ClientMessage::JoinRoom(0));
+        expect_error(sent(&mut lobby, 9));
+        assert!(lobby.drain_kicked().is_empty());
+        // spectators don't count against the limit
+        lobby.handle(9, ClientMessage::SpectateRoom(0));
+        match sent(&mut lobby, 9).as_slice() {
+            [ServerMessage::Spectating(0), ServerMessage::Settings(_)] => (),
+            other => panic!("expected to spectate, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn the_room_list_fits_in_a_frame() {
+        let mut lobby = Lobby::new(min_frame());
+        let rooms = room_list_capacity(min_frame()) + 3;
+        for conn in 0..rooms {
+            lobby.connect(conn);
+            lobby.handle(conn, ClientMessage::CreateRoom(format!("room {}", conn)));
+        }
+        lobby.drain_outbox();
+        lobby.connect(rooms);
+        lobby.handle(rooms, ClientMessage::ListRooms);
+        match sent(&mut lobby, rooms).pop() {
+            Some(ServerMessage::RoomList(list)) => {
+                assert_eq!(list.len(), room_list_capacity(min_frame()));
+                assert_eq!(list[0].id, 0);
+                let msg = ServerMessage::RoomList(list);
+                let frame = 4 + schema::encode(Kind::Message, &msg).unwrap().len() as u32;
+                assert!(frame <= min_frame());
+            },
+            other => panic!("expected a room list, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn long_room_names_are_refused_without_a_kick() {
+        let mut lobby = Lobby::new(DEFAULT_MAX_FRAME);
+        lobby.connect(0);
+        lobby.drain_outbox();
+        // within the limit in characters, though not in bytes
+        let name: String = ::std::iter::repeat('é').take(ROOM_NAME_MAX_LEN).collect();
+        lobby.handle(0, ClientMessage::CreateRoom(name.clone() + "e"));
+        expect_error(sent(&mut lobby, 0));
+        assert!(lobby.drain_kicked().is_empty());
+        lobby.handle(0, ClientMessage::CreateRoom(name));
+        match sent(&mut lobby, 0).as_slice() {
+            [ServerMessage::Joined(0, 0), ServerMessage::Settings(_)] => (),
+            other => panic!("expected to join the new room, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn offers_to_departed_players_are_closed() {
+        let mut lobby = Lobby::new(DEFAULT_MAX_FRAME);
+        room_of(&mut lobby, 2, RoomSettings::default());
+        lobby.handle(1, ClientMessage::LeaveRoom);
+        lobby.drain_outbox();
+        lobby.handle(0, ClientMessage::State(state(0, 3)));
+        lobby.handle(0, ClientMessage::TradeOffer(1, 3));
+        match sent(&mut lobby, 0).as_slice() {
+            [ServerMessage::TradeClosed(1, TradeEnd::PartnerLeft)] => (),
+            other => panic!("expected the offer closed, got {:?}", other),
+        }
+        // a state still pointing at them hears the same
+        let mut pending = state(0, 3);
+        pending.next_tetromino.2 = TradeState::Pending(1);
+        lobby.handle(0, ClientMessage::State(pending));
+        match sent(&mut lobby, 0).as_slice() {
+            [ServerMessage::TradeClosed(1, TradeEnd::PartnerLeft)] => (),
+            other => panic!("expected the offer closed, got {:?}", other),
+        }
+        assert!(lobby.drain_kicked().is_empty());
+    }
+
+    #[test]
+    fn rings_are_refused_where_they_are_off() {
+        let settings = RoomSettings {
+            trade: TradeRules { rings: false, ..TradeRules::default() },
+            ..RoomSettings::default()
+        };
+        let mut lobby = Lobby::new(DEFAULT_MAX_FRAME);
+        room_of(&mut lobby, 3, settings);
+        lobby.handle(0, ClientMessage::State(state(0, 2)));
+        lobby.drain_outbox();
+        lobby.handle(0, ClientMessage::RingPropose(vec![0, 1, 2], 2));
+        let out = lobby.drain_outbox();
+        match out.as_slice() {
+            [(0, ServerMessage::RingProposed(id, _)),
+             (0, ServerMessage::RingClosed(closed, TradeEnd::Rejected))] => assert_eq!(id, closed),
+            other => panic!("expected the ring refused, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rings_with_departed_members_are_refused() {
+        let mut lobby = Lobby::new(DEFAULT_MAX_FRAME);
+        room_of(&mut lobby, 3, RoomSettings::default());
+        lobby.handle(2, ClientMessage::LeaveRoom);
+        lobby.handle(0, ClientMessage::State(state(0, 2)));
+        lobby.drain_outbox();
+        lobby.handle(0, ClientMessage::RingPropose(vec![0, 1, 2], 2));
+        let out = lobby.drain_outbox();
+        match out.as_slice() {
+            [(0, ServerMessage::RingProposed(_, _)),
+             (0, ServerMessage::RingClosed(_, TradeEnd::PartnerLeft))] => (),
+            other => panic!("expected the ring refused, got {:?}", other),
+        }
+        assert!(lobby.drain_kicked().is_empty());
+    }
+}
@@ -0,0 +1,163 @@
+use std::time::Instant;
+
+use playerstate::PlayerState;
+
+// what brings on the next board rotation, chosen when a room is created
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum RotationTrigger {
+    // points per player scored since the last rotation
+    Score(u32),
+    // seconds since the last rotation
+    Interval(u32),
+    // lines cleared between everyone
+    Lines(u32),
+    // pieces placed between everyone
+    Pieces(u32),
+    // a majority of players asking for it with V
+    Vote,
+}
+
+impl Default for RotationTrigger {
+    fn default() -> RotationTrigger {
+        RotationTrigger::Score(50)
+    }
+}
+
+impl RotationTrigger {
+    pub fn describe(&self) -> String {
+        match *self {
+            RotationTrigger::Score(n) => format!("every {} points per player", n),
+            RotationTrigger::Interval(n) => format!("every {} seconds", n),
+            RotationTrigger::Lines(n) => format!("every {} lines", n),
+            RotationTrigger::Pieces(n) => format!("every {} pieces", n),
+            RotationTrigger::Vote => "on a majority vote".to_string(),
+        }
+    }
+
+    // the server's check on what a room's creator asked for, a trigger of 0
+    // would rotate on every state
+    pub fn check(&self) -> Result<(), String> {
+        let (n, max, unit) = match *self {
+            RotationTrigger::Score(n) => (n, 100000, "points"),
+            RotationTrigger::Interval(n) => (n, 3600, "seconds"),
+            RotationTrigger::Lines(n) => (n, 1000, "lines"),
+            RotationTrigger::Pieces(n) => (n, 10000, "pieces"),
+            RotationTrigger::Vote => return Ok(()),
+        };
+        if n == 0 || n > max {
+            return Err(format!("rotation every {} {} out of range", n, unit));
+        }
+        Ok(())
+    }
+}
+
+// summed score, lines and pieces placed.
+// Peers report their own counters, so the sums stop at the top rather than
+// overflow.
+fn totals(states: &Vec<PlayerState>) -> (u32, u32, u32) {
+    states.iter().fold((0u32, 0u32, 0u32), |(s, l, p), ps| {
+        (s.saturating_add(ps.score), l.saturating_add(ps.lines), p.saturating_add(ps.locks))
+    })
+}
+
+// counts toward the next rotation from wherever the last one left off
+pub struct RotationClock {
+    pub trigger: RotationTrigger,
+    since: Instant,
+    // summed score, lines and pieces at the last rotation, taken from the
+    // first states we see so joining a game in progress starts from zero
+    base: Option<(u32, u32, u32)>,
+}
+
+impl RotationClock {
+    pub fn new(trigger: RotationTrigger) -> RotationClock {
+        RotationClock {
+            trigger: trigger,
+            since: Instant::now(),
+            base: None,
+        }
+    }
+
+    pub fn update(&mut self, states: &Vec<PlayerState>) {
+        if self.base.is_none() && !states.is_empty() {
+            self.base = Some(totals(states));
+        }
+    }
+
+    // how far along we are, and how far there is to go, in the trigger's units
+    pub fn progress(&self, states: &Vec<PlayerState>) -> (u32, u32) {
+        let (score, lines, pieces) = totals(states);
+        let base = self.base.unwrap_or((score, lines, pieces));
+        let players = states.len() as u32;
+        match self.trigger {
+            RotationTrigger::Score(n) => (score.saturating_sub(base.0), n.saturating_mul(players)),
+            RotationTrigger::Interval(n) => (self.since.elapsed().as_secs() as u32, n),
+            RotationTrigger::Lines(n) => (lines.saturating_sub(base.1), n),
+            RotationTrigger::Pieces(n) => (pieces.saturating_sub(base.2), n),
+            RotationTrigger::Vote => {
+                let votes = states.iter().filter(|ps| ps.rotate_vote).count() as u32;
+                (votes, players / 2 + 1)
+            },
+        }
+    }
+
+    pub fn due(&self, states: &Vec<PlayerState>) -> bool {
+        let (done, goal) = self.progress(states);
+        done >= goal
+    }
+
+    // starts counting afresh once a rotation gets under way
+    pub fn restart(&mut self, states: &Vec<PlayerState>) {
+        self.since = Instant::now();
+        self.base = Some(totals(states));
+    }
+
+    pub fn label(&self, states: &Vec<PlayerState>) -> String {
+        let (done, goal) = self.progress(states);
+        if let RotationTrigger::Interval(_) = self.trigger {
+            return format!("next rotation in {}s", goal.saturating_sub(done));
+        }
+        let unit = match self.trigger {
+            RotationTrigger::Score(_) => "points",
+            RotationTrigger::Interval(_) => "seconds",
+            RotationTrigger::Lines(_) => "lines",
+            RotationTrigger::Pieces(_) => "pieces",
+            RotationTrigger::Vote => "votes",
+        };
+        format!("rotation {}/{} {}", done.min(goal), goal, unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placed_pieces_count() {
+        let mut states = vec![PlayerState::new(0), PlayerState::new(1)];
+        let mut clock = RotationClock::new(RotationTrigger::Pieces(3));
+        clock.update(&states);
+        states[0].locks = 1;
+        states[1].locks = 1;
+        assert_eq!(clock.progress(&states), (2, 3));
+        assert!(!clock.due(&states));
+    }
+
+    #[test]
+    fn huge_reported_counters_dont_overflow() {
+        let mut states = vec![PlayerState::new(0), PlayerState::new(1)];
+        let mut clock = RotationClock::new(RotationTrigger::Score(50));
+        clock.update(&states);
+        states[0].score = u32::max_value();
+        states[1].score = 10;
+        assert_eq!(clock.progress(&states), (u32::max_value(), 100));
+        assert!(clock.due(&states));
+    }
+
+    #[test]
+    fn large_goals_dont_overflow() {
+        let states = vec![PlayerState::new(0), PlayerState::new(1)];
+        let clock = RotationClock::new(RotationTrigger::Score(u32::max_value()));
+        assert_eq!(clock.progress(&states), (0, u32::max_value()));
+    }
+}
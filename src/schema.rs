@@ -0,0 +1,218 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+use serde_json::Value;
+
+// Everything we put on the wire or on disk is wrapped in an envelope carrying
+// this version. New fields must be added as `#[serde(default)]` options so
+// older readers skip them and newer readers fill them in; anything else
+// (renames, type changes, new enum variants) bumps the version and gets a
+// case in `upgrade`. We never guess at a newer version.
+//
+// 2: trade rings, trade and ring messages, TradeState::Confirm dropped
+pub const SCHEMA_VERSION: u16 = 2;
+
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+pub enum Kind {
+    Message,
+    Replay,
+    Theme,
+}
+
+#[derive(Debug)]
+pub enum SchemaError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    WrongKind(Kind),
+    UnsupportedVersion(u16),
+}
+
+impl From<io::Error> for SchemaError {
+    fn from(e: io::Error) -> SchemaError {
+        SchemaError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for SchemaError {
+    fn from(e: serde_json::Error) -> SchemaError {
+        SchemaError::Json(e)
+    }
+}
+
+#[derive(Serialize)]
+struct Envelope<'a, T: 'a> {
+    version: u16,
+    kind: Kind,
+    payload: &'a T,
+}
+
+#[derive(Deserialize)]
+struct RawEnvelope {
+    version: u16,
+    kind: Kind,
+    payload: Value,
+}
+
+pub trait Versioned: Serialize + DeserializeOwned {
+    // converts a payload written under an older schema version, by default
+    // there's no going back
+    fn upgrade(version: u16, _payload: Value) -> Result<Self, SchemaError> {
+        Err(SchemaError::UnsupportedVersion(version))
+    }
+}
+
+// for upgrades where the old payload reads as the new one
+pub fn from_payload<T: Versioned>(payload: Value) -> Result<T, SchemaError> {
+    Ok(try!(serde_json::from_value(payload)))
+}
+
+pub fn encode<T: Versioned>(kind: Kind, value: &T) -> Result<Vec<u8>, SchemaError> {
+    let envelope = Envelope {
+        version: SCHEMA_VERSION,
+        kind: kind,
+        payload: value,
+    };
+    Ok(try!(serde_json::to_vec(&envelope)))
+}
+
+pub fn decode<T: Versioned>(kind: Kind, bytes: &[u8]) -> Result<T, SchemaError> {
+    let envelope: RawEnvelope = try!(serde_json::from_slice(bytes));
+    if envelope.kind != kind {
+        return Err(SchemaError::WrongKind(envelope.kind));
+    }
+    if envelope.version > SCHEMA_VERSION {
+        Err(SchemaError::UnsupportedVersion(envelope.version))
+    }
+    else if envelope.version == SCHEMA_VERSION {
+        from_payload(envelope.payload)
+    }
+    else {
+        T::upgrade(envelope.version, envelope.payload)
+    }
+}
+
+pub fn write_file<T: Versioned>(path: &Path, kind: Kind, value: &T)
+                                -> Result<(), SchemaError> {
+    let bytes = try!(encode(kind, value));
+    let mut file = try!(File::create(path));
+    try!(file.write_all(&bytes));
+    Ok(())
+}
+
+pub fn read_file<T: Versioned>(path: &Path, kind: Kind) -> Result<T, SchemaError> {
+    let mut bytes = Vec::new();
+    let mut file = try!(File::open(path));
+    try!(file.read_to_end(&mut bytes));
+    decode(kind, &bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::{ClientMessage, ServerMessage, TradeEnd};
+    use replay::Replay;
+    use playerstate::{PlayerState, TradeState};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Sample {
+        name: String,
+        count: u32,
+    }
+
+    impl Versioned for Sample {}
+
+    fn sample() -> Sample {
+        Sample {
+            name: "sample".to_string(),
+            count: 3,
+        }
+    }
+
+    fn envelope(version: u16, kind: &str, payload: &str) -> Vec<u8> {
+        format!("{{\"version\": {}, \"kind\": \"{}\", \"payload\": {}}}", version, kind, payload)
+            .into_bytes()
+    }
+
+    #[test]
+    fn round_trips_every_kind() {
+        for &kind in [Kind::Message, Kind::Replay, Kind::Theme].iter() {
+            let bytes = encode(kind, &sample()).unwrap();
+            assert_eq!(decode::<Sample>(kind, &bytes).unwrap(), sample());
+        }
+    }
+
+    #[test]
+    fn round_trips_messages() {
+        let bytes = encode(Kind::Message, &ClientMessage::TradeOffer(2, 7)).unwrap();
+        match decode(Kind::Message, &bytes).unwrap() {
+            ClientMessage::TradeOffer(2, 7) => (),
+            other => panic!("decoded {:?}", other),
+        }
+        let bytes = encode(Kind::Message, &ServerMessage::TradeClosed(1, TradeEnd::Expired))
+            .unwrap();
+        match decode(Kind::Message, &bytes).unwrap() {
+            ServerMessage::TradeClosed(1, TradeEnd::Expired) => (),
+            other => panic!("decoded {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_the_wrong_kind() {
+        let bytes = encode(Kind::Replay, &sample()).unwrap();
+        match decode::<Sample>(Kind::Message, &bytes) {
+            Err(SchemaError::WrongKind(Kind::Replay)) => (),
+            other => panic!("decoded {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_a_future_version() {
+        let bytes = envelope(SCHEMA_VERSION + 1, "Message", r#"{"name": "sample", "count": 3}"#);
+        match decode::<Sample>(Kind::Message, &bytes) {
+            Err(SchemaError::UnsupportedVersion(v)) => assert_eq!(v, SCHEMA_VERSION + 1),
+            other => panic!("decoded {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_an_old_version_without_an_upgrade() {
+        let bytes = envelope(SCHEMA_VERSION - 1, "Message", r#"{"name": "sample", "count": 3}"#);
+        match decode::<Sample>(Kind::Message, &bytes) {
+            Err(SchemaError::UnsupportedVersion(v)) => assert_eq!(v, SCHEMA_VERSION - 1),
+            other => panic!("decoded {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_a_bad_payload() {
+        let bytes = envelope(SCHEMA_VERSION, "Message", r#"{"name": 3}"#);
+        match decode::<Sample>(Kind::Message, &bytes) {
+            Err(SchemaError::Json(_)) => (),
+            other => panic!("decoded {:?}", other),
+        }
+        match decode::<Sample>(Kind::Message, b"not json") {
+            Err(SchemaError::Json(_)) => (),
+            other => panic!("decoded {:?}", other),
+        }
+    }
+
+    #[test]
+    fn upgrades_a_version_1_replay() {
+        let mut replay = Replay::new(4);
+        let mut ps = PlayerState::new(0);
+        ps.next_tetromino.2 = TradeState::Pending(1);
+        replay.record(1, &vec![ps]);
+        // written before Confirm was dropped, with a trade in that state
+        let payload = serde_json::to_string(&replay).unwrap()
+            .replace(r#"{"Pending":1}"#, r#"{"Confirm":1}"#);
+        let bytes = envelope(1, "Replay", &payload);
+        let upgraded: Replay = decode(Kind::Replay, &bytes).unwrap();
+        assert_eq!(upgraded.room, 4);
+        assert_eq!(upgraded.frames[0].states[0].next_tetromino.2, TradeState::NoTrade);
+    }
+}
@@ -0,0 +1,163 @@
+use std::path::Path;
+
+use serde_json::Value;
+
+use playerstate::Cell;
+use schema::{Versioned, SchemaError, Kind, read_file, from_payload};
+
+pub type Color = (f32, f32, f32);
+
+// colours and looks for everything on the board. Theme files are read in the
+// usual envelope, and any field left out of one is taken from the classic
+// theme.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Theme {
+    pub name: String,
+    // I, J, L, O, S, T, Z in that order
+    pub pieces: [Color; 7],
+    pub grid: Color,
+    pub background: Color,
+    // how solid other players' boards and pieces are, 1 leaves them as
+    // translucent as the material makes them, lower fades them further
+    pub peer_opacity: f32,
+    // marks each piece's cells with its own pattern of dots, so pieces can
+    // be told apart without their colours
+    pub patterns: bool,
+    pub lighting: Lighting,
+}
+
+// how the cubes are shaded, the rest of the material's settings are kept
+// across themes
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct Lighting {
+    // alpha of the ambient term, which the lighting halves
+    pub alpha: f32,
+    // scales the cube's colour in the ambient term
+    pub ambient: f32,
+    // strength of the highlight, 0 for none
+    pub specular: f32,
+    // how tight the highlight is
+    pub shininess: f32,
+}
+
+impl Default for Lighting {
+    fn default() -> Lighting {
+        Lighting {
+            alpha: 0.8,
+            ambient: 1.0,
+            specular: 0.0,
+            shininess: 16.0,
+        }
+    }
+}
+
+impl Versioned for Theme {
+    // themes haven't changed since version 1
+    fn upgrade(version: u16, payload: Value) -> Result<Theme, SchemaError> {
+        match version {
+            1 => from_payload(payload),
+            _ => Err(SchemaError::UnsupportedVersion(version)),
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Theme {
+        Theme::classic()
+    }
+}
+
+impl Theme {
+    pub fn classic() -> Theme {
+        Theme {
+            name: "classic".to_string(),
+            pieces: [(0.0, 1.0, 1.0),
+                     (1.0, 1.0, 0.0),
+                     (1.0, 0.647, 0.0),
+                     (0.0, 0.0, 1.0),
+                     (0.0, 1.0, 0.0),
+                     (1.0, 0.0, 0.0),
+                     (0.5, 0.0, 0.5)],
+            grid: (0.5, 0.5, 0.5),
+            background: (0.0, 0.0, 0.0),
+            peer_opacity: 1.0,
+            patterns: false,
+            lighting: Lighting::default(),
+        }
+    }
+
+    // the Okabe-Ito colours, which stay apart without red-green vision; red
+    // and green pieces become vermillion and white
+    pub fn deuteranopia() -> Theme {
+        Theme {
+            name: "deuteranopia".to_string(),
+            pieces: [(0.34, 0.71, 0.91),
+                     (0.0, 0.45, 0.70),
+                     (0.90, 0.62, 0.0),
+                     (0.94, 0.89, 0.26),
+                     (0.9, 0.9, 0.9),
+                     (0.80, 0.47, 0.65),
+                     (0.84, 0.37, 0.0)],
+            patterns: true,
+            .. Theme::classic()
+        }
+    }
+
+    // as above, but reds look dark without red cones, so the Z takes a light
+    // grey and the S keeps its green
+    pub fn protanopia() -> Theme {
+        Theme {
+            name: "protanopia".to_string(),
+            pieces: [(0.34, 0.71, 0.91),
+                     (0.0, 0.45, 0.70),
+                     (0.90, 0.62, 0.0),
+                     (0.94, 0.89, 0.26),
+                     (0.0, 0.62, 0.45),
+                     (0.80, 0.47, 0.65),
+                     (0.75, 0.75, 0.75)],
+            patterns: true,
+            .. Theme::classic()
+        }
+    }
+
+    pub fn builtins() -> Vec<Theme> {
+        vec![Theme::classic(), Theme::deuteranopia(), Theme::protanopia()]
+    }
+
+    pub fn load(path: &Path) -> Result<Theme, SchemaError> {
+        read_file(path, Kind::Theme)
+    }
+
+    pub fn color(&self, cell: Cell) -> Color {
+        match cell {
+            Cell::E => self.background,
+            Cell::I => self.pieces[0],
+            Cell::J => self.pieces[1],
+            Cell::L => self.pieces[2],
+            Cell::O => self.pieces[3],
+            Cell::S => self.pieces[4],
+            Cell::T => self.pieces[5],
+            Cell::Z => self.pieces[6],
+        }
+    }
+
+    // the dots marking a piece's cells, as offsets from the cell's centre.
+    // Every piece gets a different count or arrangement.
+    pub fn pattern(&self, cell: Cell) -> &'static [(f32, f32)] {
+        if !self.patterns {
+            return &[];
+        }
+        match cell {
+            Cell::E => &[],
+            Cell::I => &[(0.0, 0.0)],
+            Cell::J => &[(-0.2, 0.2)],
+            Cell::L => &[(0.2, 0.2)],
+            Cell::O => &[(-0.2, 0.2), (0.2, 0.2), (-0.2, -0.2), (0.2, -0.2)],
+            Cell::S => &[(-0.2, -0.2), (0.2, 0.2)],
+            Cell::T => &[(-0.2, 0.2), (0.2, 0.2), (0.0, -0.2)],
+            Cell::Z => &[(-0.2, 0.2), (0.2, -0.2)],
+        }
+    }
+}
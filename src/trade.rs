@@ -0,0 +1,777 @@
+use std::time::Instant;
+
+use playerstate::{PlayerState, TradeState, TradeExpiry, Shape};
+use protocol::{ClientMessage, TradeEnd};
+
+// smallest and largest trade ring, two players just make a pairwise trade
+pub const RING_MIN: usize = 3;
+pub const RING_MAX: usize = 16;
+
+// chosen with the room, the server and every client play by the same ones
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct TradeRules {
+    pub timeout_secs: f32,
+    // an offer follows our next piece through this many drops
+    pub max_drops: u32,
+    pub rings: bool,
+}
+
+impl Default for TradeRules {
+    fn default() -> TradeRules {
+        TradeRules {
+            timeout_secs: 10.0,
+            max_drops: 2,
+            rings: true,
+        }
+    }
+}
+
+impl TradeRules {
+    pub fn check(&self) -> Result<(), String> {
+        if !(self.timeout_secs > 0.0 && self.timeout_secs <= 60.0) {
+            return Err(format!("trade timeout {} out of range", self.timeout_secs));
+        }
+        if self.max_drops == 0 || self.max_drops > 10 {
+            return Err(format!("trade drops {} out of range", self.max_drops));
+        }
+        Ok(())
+    }
+}
+
+// Both halves of a trade the server has committed. Pieces are identified by
+// the owner's locked piece count at the time of the offer, so each side swaps
+// exactly the piece it offered even if that piece has since become current.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TradeCommit {
+    pub a: usize,
+    pub a_seq: u32,
+    pub a_shape: Shape,
+    pub b: usize,
+    pub b_seq: u32,
+    pub b_shape: Shape,
+}
+
+// Every leg of a committed ring, in ring order: each member gives their shape
+// to the member after them, and the last gives to the first.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RingCommit {
+    pub legs: Vec<(usize, u32, Shape)>,
+}
+
+pub fn find_state(states: &Vec<PlayerState>, id: usize) -> Option<&PlayerState> {
+    states.iter().find(|&ref x| x.id == id)
+}
+
+fn elapsed_secs(since: Instant) -> f32 {
+    let d = since.elapsed();
+    d.as_secs() as f32 + d.subsec_nanos() as f32 / 1_000_000_000.0
+}
+
+#[derive(Debug)]
+pub struct Offer {
+    pub from: usize,
+    pub to: usize,
+    pub seq: u32,
+    pub shape: Shape,
+    made: Instant,
+    // the piece first offered, drops count from there
+    first_seq: u32,
+}
+
+#[derive(Debug)]
+pub struct Ring {
+    pub id: usize,
+    // the proposer comes first
+    pub members: Vec<usize>,
+    // each member's piece and shape once they've joined
+    joined: Vec<Option<(u32, Shape)>>,
+    // the piece each member first put in, drops count from there
+    first_seqs: Vec<Option<u32>>,
+    made: Instant,
+}
+
+// what became of an offer
+#[derive(Debug)]
+pub enum OfferResult {
+    // waiting on the other player
+    Open,
+    Committed(TradeCommit),
+    // the other player's offer was for a piece they've since played, so
+    // neither goes ahead
+    Stale(Offer),
+    // carried over more drops than the room allows, withdrawn
+    Expired,
+}
+
+// what became of a member joining a ring
+#[derive(Debug)]
+pub enum RingResult {
+    // waiting on other members, or there was no such ring to join
+    Open,
+    Committed(RingCommit),
+    // some member has played the piece they put in, so nobody's goes
+    Stale(Ring),
+    // the member has carried their piece over more drops than the room
+    // allows, so the ring is off
+    Expired(Ring),
+}
+
+// server-side record of the open offers in one room. A player is bound to at
+// most one offer or ring at a time, so no piece can go to two places.
+pub struct TradeBook {
+    offers: Vec<Offer>,
+    rings: Vec<Ring>,
+    next_ring: usize,
+}
+
+impl TradeBook {
+    pub fn new() -> TradeBook {
+        TradeBook {
+            offers: Vec::new(),
+            rings: Vec::new(),
+            next_ring: 0,
+        }
+    }
+
+    // replaces any earlier offer from the same player, committing straight
+    // away if the target already has an offer out to them. `to_pieces` is the
+    // target's piece count as last heard, their offer only commits while it
+    // still names the same piece, so both pieces are swapped at the seq they
+    // were offered at or not at all. Clients drop their offers after
+    // `max_drops` pieces themselves, the book holds them to it.
+    pub fn offer(&mut self, from: usize, to: usize, seq: u32, shape: Shape,
+                 to_pieces: Option<u32>, max_drops: u32) -> OfferResult {
+        // re-offering a later piece to the same player keeps the clock running
+        let (made, first_seq) = match self.withdraw(from) {
+            Some(ref old) if old.to == to => (old.made, old.first_seq),
+            _ => (Instant::now(), seq),
+        };
+        if seq.saturating_sub(first_seq) >= max_drops {
+            return OfferResult::Expired;
+        }
+        match self.offers.iter().position(|o| o.from == to && o.to == from) {
+            Some(pos) => {
+                let other = self.offers.remove(pos);
+                if to_pieces != Some(other.seq) {
+                    return OfferResult::Stale(other);
+                }
+                OfferResult::Committed(TradeCommit {
+                    a: other.from,
+                    a_seq: other.seq,
+                    a_shape: other.shape,
+                    b: from,
+                    b_seq: seq,
+                    b_shape: shape,
+                })
+            },
+            None => {
+                self.offers.push(Offer {
+                    from: from,
+                    to: to,
+                    seq: seq,
+                    shape: shape,
+                    made: made,
+                    first_seq: first_seq,
+                });
+                OfferResult::Open
+            },
+        }
+    }
+
+    pub fn withdraw(&mut self, from: usize) -> Option<Offer> {
+        match self.offers.iter().position(|o| o.from == from) {
+            Some(pos) => Some(self.offers.remove(pos)),
+            None => None,
+        }
+    }
+
+    pub fn decline(&mut self, from: usize, to: usize) -> Option<Offer> {
+        match self.offers.iter().position(|o| o.from == from && o.to == to) {
+            Some(pos) => Some(self.offers.remove(pos)),
+            None => None,
+        }
+    }
+
+    // drops every offer to or from a departing player, returning the ones
+    // whose offerers are still around to be told
+    pub fn remove_player(&mut self, pid: usize) -> Vec<Offer> {
+        self.withdraw(pid);
+        let (dropped, kept) = self.offers.drain(..).partition(|o| o.to == pid);
+        self.offers = kept;
+        dropped
+    }
+
+    pub fn expire(&mut self, timeout_secs: f32) -> Vec<Offer> {
+        let (expired, kept) = self.offers.drain(..)
+            .partition(|o| elapsed_secs(o.made) >= timeout_secs);
+        self.offers = kept;
+        expired
+    }
+
+    // numbers a ring, also used for proposals turned down before they
+    // open, so their proposer hears of it like any other closed ring
+    pub fn number_ring(&mut self) -> usize {
+        self.next_ring += 1;
+        self.next_ring - 1
+    }
+
+    // opens a ring with the proposer already in it, returns its id
+    pub fn propose_ring(&mut self, members: Vec<usize>, seq: u32, shape: Shape) -> usize {
+        let id = self.number_ring();
+        let mut joined = vec![None; members.len()];
+        joined[0] = Some((seq, shape));
+        let mut first_seqs = vec![None; members.len()];
+        first_seqs[0] = Some(seq);
+        self.rings.push(Ring {
+            id: id,
+            members: members,
+            joined: joined,
+            first_seqs: first_seqs,
+            made: Instant::now(),
+        });
+        id
+    }
+
+    pub fn ring(&self, id: usize) -> Option<&Ring> {
+        self.rings.iter().find(|r| r.id == id)
+    }
+
+    // records a member's piece, or updates it after a drop, and commits the
+    // ring once everybody is in. `pieces` holds each player's piece count as
+    // last heard, by player id; the ring only commits while every member is
+    // still on the piece they put in, as with pairwise offers, and no member
+    // carries theirs over more than `max_drops` pieces.
+    pub fn join_ring(&mut self, id: usize, pid: usize, seq: u32, shape: Shape,
+                     pieces: &[Option<u32>], max_drops: u32) -> RingResult {
+        let pos = match self.rings.iter().position(|r| r.id == id) {
+            Some(pos) => pos,
+            None => return RingResult::Open,
+        };
+        let i = match self.rings[pos].members.iter().position(|&m| m == pid) {
+            Some(i) => i,
+            None => return RingResult::Open,
+        };
+        let first_seq = *self.rings[pos].first_seqs[i].get_or_insert(seq);
+        if seq.saturating_sub(first_seq) >= max_drops {
+            return RingResult::Expired(self.rings.remove(pos));
+        }
+        self.rings[pos].joined[i] = Some((seq, shape));
+        if self.rings[pos].joined.iter().any(|j| j.is_none()) {
+            return RingResult::Open;
+        }
+        let ring = self.rings.remove(pos);
+        let current = ring.members.iter().zip(ring.joined.iter())
+            .all(|(&m, j)| pieces.get(m) == Some(&Some(j.unwrap().0)));
+        if !current {
+            return RingResult::Stale(ring);
+        }
+        RingResult::Committed(RingCommit {
+            legs: ring.members.iter().zip(ring.joined.iter())
+                .map(|(&m, j)| (m, j.unwrap().0, j.unwrap().1))
+                .collect(),
+        })
+    }
+
+    pub fn close_ring(&mut self, id: usize) -> Option<Ring> {
+        match self.rings.iter().position(|r| r.id == id) {
+            Some(pos) => Some(self.rings.remove(pos)),
+            None => None,
+        }
+    }
+
+    // drops every ring a player is part of, joined or merely invited, bar the
+    // one they're joining, so the others fall back to trading as they were
+    pub fn leave_rings(&mut self, pid: usize, except: Option<usize>) -> Vec<Ring> {
+        let (left, kept) = self.rings.drain(..)
+            .partition(|r| r.members.contains(&pid) && Some(r.id) != except);
+        self.rings = kept;
+        left
+    }
+
+    pub fn expire_rings(&mut self, timeout_secs: f32) -> Vec<Ring> {
+        let (expired, kept) = self.rings.drain(..)
+            .partition(|r| elapsed_secs(r.made) >= timeout_secs);
+        self.rings = kept;
+        expired
+    }
+}
+
+// the client's side of trading: mirrors our offer to the server and applies
+// whatever the server commits
+pub struct TradeSession {
+    pub rules: TradeRules,
+    // what we're offering and since when
+    started: Option<(TradeState, Instant)>,
+    // target and piece of the offer the server holds for us
+    sent: Option<(usize, u32)>,
+    declined: Vec<usize>,
+    // the ring we're in, once the server has numbered it, and the piece we
+    // last put into it
+    ring: Option<usize>,
+    ring_seq: u32,
+    // rings others have asked us into, with their members
+    invites: Vec<(usize, Vec<usize>)>,
+    outbox: Vec<ClientMessage>,
+    // for the player to read, in the chat or status line
+    notices: Vec<String>,
+}
+
+impl TradeSession {
+    pub fn new(rules: TradeRules) -> TradeSession {
+        TradeSession {
+            rules: rules,
+            started: None,
+            sent: None,
+            declined: Vec::new(),
+            ring: None,
+            ring_seq: 0,
+            invites: Vec::new(),
+            outbox: Vec::new(),
+            notices: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, my_state: &mut PlayerState, states: &Vec<PlayerState>) {
+        let my_id = my_state.id;
+        self.declined.retain(|&from| offers_to(states, from, my_id));
+        self.invites.retain(|&(_, ref members)| {
+            members.iter().all(|&m| find_state(states, m).is_some())
+        });
+        // we've moved on from our ring, by running out of drops or by
+        // making a pairwise offer instead
+        let in_ring = match my_state.next_tetromino.2 {
+            TradeState::Ring(_) => true,
+            _ => false,
+        };
+        if !in_ring {
+            if let Some(id) = self.ring.take() {
+                self.outbox.push(ClientMessage::RingLeave(id));
+            }
+        }
+
+        match my_state.next_tetromino.2.clone() {
+            TradeState::Pending(target) => {
+                if find_state(states, target).is_none() || !self.tick(my_state) {
+                    self.cancel(my_state);
+                }
+            },
+            TradeState::Ring(members) => {
+                if members.iter().any(|&m| find_state(states, m).is_none())
+                    || !self.tick(my_state) {
+                    self.leave_ring(my_state);
+                }
+                else if let Some(id) = self.ring {
+                    // the piece we put in has dropped and the offer moved on
+                    if self.ring_seq != my_state.pieces {
+                        self.ring_seq = my_state.pieces;
+                        self.outbox.push(ClientMessage::RingJoin(id, my_state.pieces));
+                    }
+                }
+            },
+            _ => {
+                self.started = None;
+                my_state.trade_expiry = None;
+            },
+        }
+        self.sync(my_state);
+    }
+
+    pub fn drain_outbox(&mut self) -> Vec<ClientMessage> {
+        self.outbox.drain(..).collect()
+    }
+
+    pub fn drain_notices(&mut self) -> Vec<String> {
+        self.notices.drain(..).collect()
+    }
+
+    // asks the given players to pass their next pieces round, ours going to
+    // the first of them and the last of theirs coming to us
+    pub fn propose_ring(&mut self, my_state: &mut PlayerState, others: &Vec<usize>) {
+        if my_state.paused || !self.rules.rings || others.len() + 1 < RING_MIN {
+            return;
+        }
+        self.leave_ring(my_state);
+        let mut members = vec![my_state.id];
+        members.extend(others.iter().cloned().filter(|&id| id != my_state.id));
+        members.truncate(RING_MAX);
+        my_state.next_tetromino.2 = TradeState::Ring(members.clone());
+        self.ring_seq = my_state.pieces;
+        self.outbox.push(ClientMessage::RingPropose(members, my_state.pieces));
+    }
+
+    // joins the first ring we've been asked into, or else answers the first
+    // pairwise offer made to us
+    pub fn accept(&mut self, my_state: &mut PlayerState, states: &Vec<PlayerState>) {
+        if my_state.paused {
+            return;
+        }
+        if !self.invites.is_empty() {
+            let (id, members) = self.invites.remove(0);
+            self.leave_ring(my_state);
+            my_state.next_tetromino.2 = TradeState::Ring(members);
+            self.ring = Some(id);
+            self.ring_seq = my_state.pieces;
+            self.outbox.push(ClientMessage::RingJoin(id, my_state.pieces));
+        }
+        else if let Some(from) = self.first_offer(my_state, states) {
+            self.leave_ring(my_state);
+            my_state.next_tetromino.2 = TradeState::Pending(from);
+        }
+    }
+
+    pub fn decline(&mut self, my_state: &mut PlayerState, states: &Vec<PlayerState>) {
+        if !self.invites.is_empty() {
+            let (id, _) = self.invites.remove(0);
+            self.outbox.push(ClientMessage::RingLeave(id));
+        }
+        else if let Some(from) = self.first_offer(my_state, states) {
+            self.declined.push(from);
+            self.outbox.push(ClientMessage::TradeDecline(from));
+        }
+    }
+
+    pub fn withdraw(&mut self, my_state: &mut PlayerState) {
+        match my_state.next_tetromino.2 {
+            TradeState::Pending(_) => self.cancel(my_state),
+            TradeState::Ring(_) => self.leave_ring(my_state),
+            _ => (),
+        }
+    }
+
+    pub fn cancel(&mut self, my_state: &mut PlayerState) {
+        self.started = None;
+        my_state.next_tetromino.2 = TradeState::NoTrade;
+        my_state.trade_expiry = None;
+    }
+
+    pub fn committed(&mut self, my_state: &mut PlayerState, commit: &TradeCommit) {
+        let (seq, shape) =
+            if commit.a == my_state.id { (commit.a_seq, commit.b_shape) }
+            else if commit.b == my_state.id { (commit.b_seq, commit.a_shape) }
+            else { return };
+        if !my_state.receive_piece(seq, shape) {
+            self.notices.push("Trade came too late, the piece had already dropped".to_string());
+        }
+        self.cancel(my_state);
+        // we may have re-offered a newer piece after the one that was taken
+        if let Some((_, sent_seq)) = self.sent {
+            if sent_seq != seq {
+                self.outbox.push(ClientMessage::TradeWithdraw);
+            }
+        }
+        self.sent = None;
+    }
+
+    pub fn ring_proposed(&mut self, my_state: &PlayerState, id: usize, members: Vec<usize>) {
+        if members[0] == my_state.id {
+            if let TradeState::Ring(_) = my_state.next_tetromino.2 {
+                self.ring = Some(id);
+                // the piece moved on while the proposal was in flight
+                if self.ring_seq != my_state.pieces {
+                    self.ring_seq = my_state.pieces;
+                    self.outbox.push(ClientMessage::RingJoin(id, my_state.pieces));
+                }
+            }
+            else {
+                // we backed out before the server numbered it
+                self.outbox.push(ClientMessage::RingLeave(id));
+            }
+        }
+        else if members.contains(&my_state.id) {
+            self.invites.push((id, members));
+        }
+    }
+
+    pub fn ring_committed(&mut self, my_state: &mut PlayerState, commit: &RingCommit) {
+        let n = commit.legs.len();
+        if let Some(i) = commit.legs.iter().position(|&(m, _, _)| m == my_state.id) {
+            let (_, seq, _) = commit.legs[i];
+            let (_, _, shape) = commit.legs[(i + n - 1) % n];
+            if !my_state.receive_piece(seq, shape) {
+                self.notices.push("Ring trade came too late, the piece had already dropped"
+                                  .to_string());
+            }
+            self.ring = None;
+            self.cancel(my_state);
+        }
+    }
+
+    // someone backed out or time ran out, everyone goes back to no trade
+    pub fn ring_closed(&mut self, my_state: &mut PlayerState, id: usize, why: TradeEnd) {
+        self.invites.retain(|&(i, _)| i != id);
+        if self.ring == Some(id) {
+            self.notices.push(format!("Trade ring {}", why.describe()));
+            self.ring = None;
+            self.cancel(my_state);
+        }
+    }
+
+    fn leave_ring(&mut self, my_state: &mut PlayerState) {
+        if let Some(id) = self.ring.take() {
+            self.outbox.push(ClientMessage::RingLeave(id));
+        }
+        if let TradeState::Ring(_) = my_state.next_tetromino.2 {
+            self.cancel(my_state);
+        }
+    }
+
+    pub fn closed(&mut self, my_state: &mut PlayerState, to: usize, why: TradeEnd) {
+        self.notices.push(format!("Offer to P{} {}", to, why.describe()));
+        if let TradeState::Pending(target) = my_state.next_tetromino.2 {
+            if target == to {
+                self.cancel(my_state);
+            }
+        }
+        self.sent = None;
+    }
+
+    // tells the server whenever our offer, or the piece behind it, changes
+    fn sync(&mut self, my_state: &PlayerState) {
+        let wanted = match my_state.next_tetromino.2 {
+            TradeState::Pending(target) => Some((target, my_state.pieces)),
+            _ => None,
+        };
+        if wanted != self.sent {
+            self.outbox.push(match wanted {
+                Some((target, seq)) => ClientMessage::TradeOffer(target, seq),
+                None => ClientMessage::TradeWithdraw,
+            });
+            self.sent = wanted;
+        }
+    }
+
+    // counts down a pending offer or ring, false once it has run out of time
+    fn tick(&mut self, my_state: &mut PlayerState) -> bool {
+        let fresh = match self.started {
+            Some((ref t, _)) => *t != my_state.next_tetromino.2,
+            None => true,
+        };
+        if fresh {
+            self.started = Some((my_state.next_tetromino.2.clone(), Instant::now()));
+            my_state.trade_expiry = Some(TradeExpiry {
+                secs_left: self.rules.timeout_secs,
+                drops_left: self.rules.max_drops,
+            });
+        }
+        let secs_left = match self.started {
+            Some((_, since)) => self.rules.timeout_secs - elapsed_secs(since),
+            None => self.rules.timeout_secs,
+        };
+        if let Some(ref mut expiry) = my_state.trade_expiry {
+            expiry.secs_left = secs_left.max(0.0);
+        }
+        secs_left > 0.0
+    }
+
+    fn first_offer(&self, my_state: &PlayerState, states: &Vec<PlayerState>) -> Option<usize> {
+        states.iter()
+            .filter(|ps| ps.id != my_state.id && !self.declined.contains(&ps.id))
+            .find(|ps| offers_to(states, ps.id, my_state.id))
+            .map(|ps| ps.id)
+    }
+}
+
+fn offers_to(states: &Vec<PlayerState>, from: usize, to: usize) -> bool {
+    match find_state(states, from) {
+        Some(ps) => match ps.next_tetromino.2 {
+            TradeState::Pending(id) => id == to,
+            _ => false,
+        },
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use playerstate::{PlayerState, TradeState, ISHAPE, JSHAPE, OSHAPE};
+
+    fn committed(result: OfferResult) -> TradeCommit {
+        match result {
+            OfferResult::Committed(commit) => commit,
+            other => panic!("expected a commit, got {:?}", other),
+        }
+    }
+
+    fn player(id: usize, pieces: u32, next: Shape) -> PlayerState {
+        let mut ps = PlayerState::new(id);
+        ps.pieces = pieces;
+        ps.next_tetromino = (next, 0, TradeState::NoTrade);
+        ps
+    }
+
+    #[test]
+    fn crossing_offers_commit_once() {
+        let mut book = TradeBook::new();
+        match book.offer(0, 1, 5, ISHAPE, Some(5), 2) {
+            OfferResult::Open => (),
+            other => panic!("expected an open offer, got {:?}", other),
+        }
+        let commit = committed(book.offer(1, 0, 5, JSHAPE, Some(5), 2));
+        assert_eq!((commit.a, commit.a_seq, commit.a_shape), (0, 5, ISHAPE));
+        assert_eq!((commit.b, commit.b_seq, commit.b_shape), (1, 5, JSHAPE));
+        // neither offer outlives the trade
+        assert!(book.withdraw(0).is_none());
+        assert!(book.withdraw(1).is_none());
+    }
+
+    #[test]
+    fn both_sides_swap_the_offered_pieces() {
+        let mut a = player(0, 5, ISHAPE);
+        let mut b = player(1, 5, JSHAPE);
+        let mut book = TradeBook::new();
+        book.offer(0, 1, 5, ISHAPE, Some(5), 2);
+        let commit = committed(book.offer(1, 0, 5, JSHAPE, Some(5), 2));
+        let rules = TradeRules::default();
+        TradeSession::new(rules).committed(&mut a, &commit);
+        TradeSession::new(rules).committed(&mut b, &commit);
+        assert_eq!(a.next_tetromino.0, JSHAPE);
+        assert_eq!(b.next_tetromino.0, ISHAPE);
+    }
+
+    #[test]
+    fn counter_offer_after_the_piece_locked_is_rejected() {
+        let mut book = TradeBook::new();
+        book.offer(0, 1, 5, ISHAPE, None, 2);
+        // player 0 has played on since making the offer
+        match book.offer(1, 0, 3, JSHAPE, Some(6), 2) {
+            OfferResult::Stale(other) => assert_eq!((other.from, other.seq), (0, 5)),
+            other => panic!("expected a stale offer, got {:?}", other),
+        }
+        // and neither offer is left to commit later
+        assert!(book.withdraw(0).is_none());
+        assert!(book.withdraw(1).is_none());
+    }
+
+    #[test]
+    fn late_commit_substitutes_nothing() {
+        let mut a = player(0, 7, OSHAPE);
+        let commit = TradeCommit {
+            a: 0,
+            a_seq: 5,
+            a_shape: ISHAPE,
+            b: 1,
+            b_seq: 5,
+            b_shape: JSHAPE,
+        };
+        let mut session = TradeSession::new(TradeRules::default());
+        session.committed(&mut a, &commit);
+        assert_eq!(a.next_tetromino.0, OSHAPE);
+        assert_eq!(session.drain_notices().len(), 1);
+    }
+
+    #[test]
+    fn commit_reaches_the_falling_piece() {
+        let mut a = player(0, 6, OSHAPE);
+        a.tetromino = (ISHAPE, 0);
+        assert!(a.receive_piece(5, JSHAPE));
+        assert_eq!(a.tetromino.0, JSHAPE);
+        assert_eq!(a.next_tetromino.0, OSHAPE);
+    }
+
+    #[test]
+    fn withdraw_racing_accept_leaves_no_trade() {
+        let mut book = TradeBook::new();
+        book.offer(0, 1, 5, ISHAPE, None, 2);
+        book.withdraw(0);
+        // player 1's acceptance arrives after the withdrawal
+        match book.offer(1, 0, 2, JSHAPE, Some(5), 2) {
+            OfferResult::Open => (),
+            other => panic!("expected an open offer, got {:?}", other),
+        }
+        // which stands as an offer of its own until withdrawn in turn
+        let offer = book.withdraw(1).unwrap();
+        assert_eq!((offer.from, offer.to, offer.seq), (1, 0, 2));
+    }
+
+    #[test]
+    fn withdrawing_after_the_commit_changes_nothing() {
+        let mut book = TradeBook::new();
+        book.offer(0, 1, 5, ISHAPE, Some(2), 2);
+        committed(book.offer(1, 0, 2, JSHAPE, Some(5), 2));
+        assert!(book.withdraw(0).is_none());
+    }
+
+    #[test]
+    fn offers_carried_past_the_drop_limit_expire() {
+        let mut book = TradeBook::new();
+        book.offer(0, 1, 5, ISHAPE, None, 2);
+        // one drop later it's still the same offer
+        match book.offer(0, 1, 6, JSHAPE, None, 2) {
+            OfferResult::Open => (),
+            other => panic!("expected an open offer, got {:?}", other),
+        }
+        match book.offer(0, 1, 7, OSHAPE, None, 2) {
+            OfferResult::Expired => (),
+            other => panic!("expected the offer to expire, got {:?}", other),
+        }
+        assert!(book.withdraw(0).is_none());
+        // offering to someone else starts afresh
+        book.offer(0, 1, 5, ISHAPE, None, 2);
+        match book.offer(0, 2, 7, OSHAPE, None, 2) {
+            OfferResult::Open => (),
+            other => panic!("expected an open offer, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn ring_commits_once_everyone_is_in() {
+        let mut book = TradeBook::new();
+        let pieces = vec![Some(4), Some(7), Some(2)];
+        let id = book.propose_ring(vec![0, 1, 2], 4, ISHAPE);
+        match book.join_ring(id, 1, 7, JSHAPE, &pieces, 2) {
+            RingResult::Open => (),
+            other => panic!("expected the ring to wait, got {:?}", other),
+        }
+        let commit = match book.join_ring(id, 2, 2, OSHAPE, &pieces, 2) {
+            RingResult::Committed(commit) => commit,
+            other => panic!("expected a commit, got {:?}", other),
+        };
+        assert_eq!(commit.legs, vec![(0, 4, ISHAPE), (1, 7, JSHAPE), (2, 2, OSHAPE)]);
+        assert!(book.ring(id).is_none());
+
+        // each member gets the piece of the one before them
+        let mut a = player(0, 4, ISHAPE);
+        TradeSession::new(TradeRules::default()).ring_committed(&mut a, &commit);
+        assert_eq!(a.next_tetromino.0, OSHAPE);
+    }
+
+    #[test]
+    fn ring_with_a_played_piece_is_rejected() {
+        let mut book = TradeBook::new();
+        let id = book.propose_ring(vec![0, 1, 2], 4, ISHAPE);
+        book.join_ring(id, 1, 7, JSHAPE, &[], 2);
+        // the proposer has locked their piece since proposing
+        match book.join_ring(id, 2, 2, OSHAPE, &[Some(5), Some(7), Some(2)], 2) {
+            RingResult::Stale(ring) => assert_eq!(ring.members, vec![0, 1, 2]),
+            other => panic!("expected a stale ring, got {:?}", other),
+        }
+        assert!(book.ring(id).is_none());
+    }
+
+    #[test]
+    fn ring_members_carried_past_the_drop_limit_expire() {
+        let mut book = TradeBook::new();
+        let id = book.propose_ring(vec![0, 1, 2], 4, ISHAPE);
+        book.join_ring(id, 1, 7, JSHAPE, &[], 2);
+        book.join_ring(id, 0, 5, JSHAPE, &[], 2);
+        match book.join_ring(id, 0, 6, OSHAPE, &[], 2) {
+            RingResult::Expired(ring) => assert_eq!(ring.id, id),
+            other => panic!("expected the ring to expire, got {:?}", other),
+        }
+        assert!(book.ring(id).is_none());
+    }
+
+    #[test]
+    fn closed_ring_becomes_a_notice() {
+        let mut a = player(0, 4, ISHAPE);
+        let mut session = TradeSession::new(TradeRules::default());
+        session.propose_ring(&mut a, &vec![1, 2]);
+        session.ring_proposed(&a, 3, vec![0, 1, 2]);
+        session.ring_closed(&mut a, 3, TradeEnd::PartnerLeft);
+        assert_eq!(a.next_tetromino.2, TradeState::NoTrade);
+        assert_eq!(session.drain_notices(),
+                   vec!["Trade ring was called off, a player left".to_string()]);
+    }
+}
@@ -0,0 +1,175 @@
+use playerstate::{PlayerState, Shape, shape_letter};
+use trade::{TradeCommit, RingCommit, find_state};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TradeRecord {
+    pub tick: u32,
+    pub from: usize,
+    pub to: usize,
+    pub shape: Shape,
+    // lines cleared when the piece locked on the receiver's board, None
+    // until then or if we missed it
+    #[serde(default)]
+    pub lines: Option<u32>,
+}
+
+// the receiver's counts as their received piece started to fall
+#[derive(Copy, Clone)]
+struct Falling {
+    lines: u32,
+    locks: u32,
+}
+
+// a received piece we're waiting to see lock
+struct Watch {
+    record: usize,
+    player: usize,
+    // the receiver's piece count when the piece was their next one
+    seq: u32,
+    falling: Option<Falling>,
+}
+
+// every trade seen in this game, pairwise or ring
+pub struct TradeLog {
+    pub records: Vec<TradeRecord>,
+    watching: Vec<Watch>,
+}
+
+impl TradeLog {
+    pub fn new() -> TradeLog {
+        TradeLog {
+            records: Vec::new(),
+            watching: Vec::new(),
+        }
+    }
+
+    pub fn committed(&mut self, tick: u32, commit: &TradeCommit) {
+        self.push(tick, commit.a, commit.b, commit.a_shape, commit.b_seq);
+        self.push(tick, commit.b, commit.a, commit.b_shape, commit.a_seq);
+    }
+
+    pub fn ring_committed(&mut self, tick: u32, commit: &RingCommit) {
+        let n = commit.legs.len();
+        for i in 0..n {
+            let (from, _, shape) = commit.legs[i];
+            let (to, seq, _) = commit.legs[(i + 1) % n];
+            self.push(tick, from, to, shape, seq);
+        }
+    }
+
+    fn push(&mut self, tick: u32, from: usize, to: usize, shape: Shape, seq: u32) {
+        self.watching.push(Watch {
+            record: self.records.len(),
+            player: to,
+            seq: seq,
+            falling: None,
+        });
+        self.records.push(TradeRecord {
+            tick: tick,
+            from: from,
+            to: to,
+            shape: shape,
+            lines: None,
+        });
+    }
+
+    // follows received pieces from falling to locked. States may arrive
+    // coalesced, so a piece whose fall or lock we never saw goes unscored.
+    pub fn update(&mut self, states: &Vec<PlayerState>) {
+        let mut done = Vec::new();
+        for (i, w) in self.watching.iter_mut().enumerate() {
+            let ps = match find_state(states, w.player) {
+                Some(ps) => ps,
+                None => continue,
+            };
+            match w.falling {
+                None => {
+                    if ps.pieces == w.seq + 1 {
+                        w.falling = Some(Falling {
+                            lines: ps.lines,
+                            locks: ps.locks,
+                        });
+                    }
+                    else if ps.pieces > w.seq + 1 {
+                        done.push(i);
+                    }
+                },
+                Some(before) => {
+                    if ps.locks > before.locks {
+                        if ps.locks == before.locks + 1 {
+                            self.records[w.record].lines =
+                                Some(ps.lines.saturating_sub(before.lines));
+                        }
+                        done.push(i);
+                    }
+                },
+            }
+        }
+        for i in done.into_iter().rev() {
+            self.watching.remove(i);
+        }
+    }
+
+    pub fn summary(&self) -> Vec<String> {
+        let mut lines = vec![format!("{} trades this game", self.records.len())];
+        let mut players: Vec<usize> = self.records.iter().map(|r| r.to).collect();
+        players.sort();
+        players.dedup();
+        for id in players {
+            let received: Vec<&TradeRecord> =
+                self.records.iter().filter(|r| r.to == id).collect();
+            let cleared = received.iter().filter_map(|r| r.lines)
+                .fold(0u32, |acc, n| acc.saturating_add(n));
+            lines.push(format!("  P{} received {} pieces, clearing {} lines with them",
+                               id, received.len(), cleared));
+        }
+        for r in self.records.iter() {
+            let cleared = match r.lines {
+                Some(n) => format!("{} lines", n),
+                None => "lines unknown".to_string(),
+            };
+            lines.push(format!("  tick {}: P{} gave {} to P{} ({})",
+                               r.tick, r.from, shape_letter(r.shape), r.to, cleared));
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use playerstate::{ISHAPE, JSHAPE};
+
+    // player 1 receives an I as their sixth piece
+    fn received() -> (TradeLog, PlayerState) {
+        let mut log = TradeLog::new();
+        log.committed(0, &TradeCommit {
+            a: 0,
+            a_seq: 5,
+            a_shape: ISHAPE,
+            b: 1,
+            b_seq: 5,
+            b_shape: JSHAPE,
+        });
+        let mut ps = PlayerState::new(1);
+        ps.pieces = 5;
+        (log, ps)
+    }
+
+    fn lines_for(log: &TradeLog, to: usize) -> Option<u32> {
+        log.records.iter().find(|r| r.to == to).unwrap().lines
+    }
+
+    #[test]
+    fn lines_are_counted_when_the_piece_locks() {
+        let (mut log, mut ps) = received();
+        ps.pieces = 6;
+        ps.lines = 10;
+        log.update(&vec![ps.clone()]);
+        ps.pieces = 7;
+        ps.locks = 1;
+        ps.lines = 12;
+        log.update(&vec![ps]);
+        assert_eq!(lines_for(&log, 1), Some(2));
+    }
+}
@@ -0,0 +1,321 @@
+use std::io;
+use std::io::prelude::*;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use playerstate::{PlayerState, Cell, ROWS, COLS, TradeState, cell_of_shape};
+use multiplayer::Mp;
+use game::Game;
+use trade::TradeSession;
+use theme::{Theme, Color};
+
+// how long to wait between frames
+const FRAME_MS: u64 = 30;
+// chat lines shown under the boards
+const CHAT_LINES: usize = 4;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum TermKey {
+    Char(char),
+    Up,
+    Down,
+    Left,
+    Right,
+    Quit,
+}
+
+// puts the terminal into raw mode for as long as this lives, handing back
+// whatever settings it had before
+struct RawMode {
+    saved: Option<String>,
+}
+
+impl RawMode {
+    fn enter() -> RawMode {
+        let saved = stty(&["-g"]).map(|s| s.trim().to_string());
+        if saved.is_some() {
+            let _ = stty(&["raw", "-echo"]);
+        }
+        RawMode { saved: saved }
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        if let Some(ref saved) = self.saved {
+            let _ = stty(&[&saved[..]]);
+        }
+        print!("\x1b[0m\x1b[?25h\r\n");
+        let _ = io::stdout().flush();
+    }
+}
+
+// stty works on the terminal on its stdin, so it gets ours. None if there's
+// no terminal to set, when we carry on with line-buffered input.
+fn stty(args: &[&str]) -> Option<String> {
+    let out = Command::new("stty").args(args).stdin(Stdio::inherit()).output().ok();
+    match out {
+        Some(ref out) if out.status.success() =>
+            Some(String::from_utf8_lossy(&out.stdout).into_owned()),
+        _ => None,
+    }
+}
+
+// bytes from stdin as they come, read on their own thread so the game
+// keeps running between key presses
+fn spawn_key_reader() -> Receiver<u8> {
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        for byte in io::stdin().bytes() {
+            match byte {
+                Ok(b) => if tx.send(b).is_err() { break },
+                Err(_) => break,
+            }
+        }
+    });
+    rx
+}
+
+// turns raw bytes into keys, arrows arriving as ESC [ A to D. An arrow cut
+// off by the end of a read is left in the buffer to finish with the next.
+fn parse_keys(bytes: &mut Vec<u8>) -> Vec<TermKey> {
+    let mut keys = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == 0x1b {
+            match (bytes.get(i + 1), bytes.get(i + 2)) {
+                (None, _) | (Some(&b'['), None) => break,
+                (Some(&b'['), Some(&b)) => {
+                    match b {
+                        b'A' => keys.push(TermKey::Up),
+                        b'B' => keys.push(TermKey::Down),
+                        b'C' => keys.push(TermKey::Right),
+                        b'D' => keys.push(TermKey::Left),
+                        _ => (),
+                    }
+                    i += 3;
+                    continue;
+                },
+                _ => (),
+            }
+        }
+        match bytes[i] {
+            // ctrl-c and ctrl-d, which raw mode no longer turns into signals
+            3 | 4 => keys.push(TermKey::Quit),
+            b => keys.push(TermKey::Char((b as char).to_ascii_lowercase())),
+        }
+        i += 1;
+    }
+    bytes.drain(..i);
+    keys
+}
+
+// the same keys as the window, less the ones for the picker and the view
+fn handle_key(key: TermKey, my_state: &mut PlayerState, trade: &mut TradeSession,
+              states: &Vec<PlayerState>) {
+    let ids: Vec<usize> = states.iter().map(|s| s.id).collect();
+    match key {
+        TermKey::Up | TermKey::Char('w') => my_state.rotate_tetromino(),
+        TermKey::Down | TermKey::Char('s') => my_state.move_down(),
+        TermKey::Left | TermKey::Char('a') => my_state.move_left(),
+        TermKey::Right | TermKey::Char('d') => my_state.move_right(),
+        TermKey::Char(' ') => my_state.drop(),
+        TermKey::Char('p') => my_state.paused = !my_state.paused,
+        TermKey::Char('v') => my_state.rotate_vote = !my_state.rotate_vote,
+        TermKey::Char('e') => my_state.toggle_swap(1, &ids),
+        TermKey::Char('c') => my_state.toggle_swap(-1, &ids),
+        TermKey::Char('y') => trade.accept(my_state, states),
+        TermKey::Char('z') => trade.decline(my_state, states),
+        TermKey::Char('x') => trade.withdraw(my_state),
+        _ => (),
+    }
+}
+
+// plays the game in the terminal until q or ctrl-c
+pub fn run(mp: &mut Mp, game: &mut Game, theme: &Theme) {
+    let raw = RawMode::enter();
+    let keys = spawn_key_reader();
+    let mut unparsed = Vec::new();
+    print!("\x1b[2J\x1b[?25l");
+
+    loop {
+        game.poll(mp);
+        let states = game.states();
+        game.update(mp, &states);
+        game.my_state.take_events();
+
+        unparsed.extend(keys.try_iter());
+        let pressed = parse_keys(&mut unparsed);
+        if pressed.iter().any(|&k| k == TermKey::Quit || k == TermKey::Char('q')) {
+            break;
+        }
+        if game.my_id.is_some() && !pressed.is_empty() {
+            for key in pressed {
+                handle_key(key, &mut game.my_state, &mut game.trade, &states);
+            }
+            mp.issue_update(game.my_state.clone());
+        }
+
+        if !states.is_empty() {
+            print!("{}", render(&states, game, mp, theme));
+            let _ = io::stdout().flush();
+        }
+        game.step(mp, &states);
+        thread::sleep(Duration::from_millis(FRAME_MS));
+    }
+    drop(raw);
+}
+
+fn bg(color: Color) -> String {
+    format!("\x1b[48;2;{};{};{}m", (color.0 * 255.0) as u8, (color.1 * 255.0) as u8,
+            (color.2 * 255.0) as u8)
+}
+
+fn cell_letter(cell: Cell) -> char {
+    match cell {
+        Cell::E => ' ',
+        Cell::I => 'I',
+        Cell::J => 'J',
+        Cell::L => 'L',
+        Cell::O => 'O',
+        Cell::S => 'S',
+        Cell::T => 'T',
+        Cell::Z => 'Z',
+    }
+}
+
+// two columns per cell so cells come out roughly square
+fn paint(out: &mut String, cell: Cell, theme: &Theme) {
+    if cell == Cell::E {
+        out.push_str("\x1b[0m  ");
+        return;
+    }
+    out.push_str(&bg(theme.color(cell)));
+    out.push_str("\x1b[30m");
+    // with patterns on, the piece's letter marks it out without colour
+    let mark = if theme.patterns { cell_letter(cell) } else { ' ' };
+    out.push(mark);
+    out.push(mark);
+}
+
+// a player's board with their falling piece, top row first
+fn board_rows(ps: &PlayerState) -> Vec<[Cell; COLS]> {
+    let mut board = ps.board;
+    let piece = cell_of_shape(ps.tetromino.0);
+    for r in 0..4 {
+        for c in 0..4 {
+            if ps.tetromino.0[ps.tetromino.1][r][c] != 0 {
+                let (br, bc) = (ps.tetro_pos.0 + r as i8, ps.tetro_pos.1 + c as i8);
+                if br >= 0 && (br as usize) < ROWS && bc >= 0 && (bc as usize) < COLS {
+                    board[br as usize][bc as usize] = piece;
+                }
+            }
+        }
+    }
+    (0..ROWS - 2).rev().map(|r| board[r]).collect()
+}
+
+fn next_rows(ps: &PlayerState) -> Vec<[Cell; COLS]> {
+    let (shape, rot) = (ps.next_tetromino.0, ps.next_tetromino.1);
+    let cell = cell_of_shape(shape);
+    (0..4).rev().map(|r| {
+        let mut row = [Cell::E; COLS];
+        for c in 0..4 {
+            if shape[rot][r][c] != 0 {
+                row[COLS / 2 - 2 + c] = cell;
+            }
+        }
+        row
+    }).collect()
+}
+
+// what a player's next piece is up to, and whether it's headed our way
+fn trade_label(ps: &PlayerState, my_id: Option<usize>) -> (String, bool) {
+    let to_me = my_id.map_or(false, |id| id != ps.id && ps.next_tetromino.2.offers_to(id));
+    let label = match ps.next_tetromino.2 {
+        TradeState::NoTrade => String::new(),
+        TradeState::Pending(t) => format!("-> P{}", t),
+        TradeState::Ring(ref m) => format!("ring of {}", m.len()),
+    };
+    (label, to_me)
+}
+
+// the whole screen, boards side by side
+fn render(states: &Vec<PlayerState>, game: &Game, mp: &Mp, theme: &Theme) -> String {
+    let width = COLS * 2 + 2;
+    let mut out = String::from("\x1b[H");
+
+    // names and scores
+    for ps in states.iter() {
+        let name = if ps.name.is_empty() { format!("P{}", ps.id) } else { ps.name.clone() };
+        let me = if Some(ps.id) == game.my_id { "*" } else { " " };
+        let head = format!("{}{} {}", me, name, ps.score);
+        out.push_str(&format!("{:<w$}  ", head.chars().take(width).collect::<String>(),
+                              w = width));
+    }
+    out.push_str("\x1b[K\r\n");
+
+    let nexts: Vec<Vec<[Cell; COLS]>> = states.iter().map(next_rows).collect();
+    let boards: Vec<Vec<[Cell; COLS]>> = states.iter().map(board_rows).collect();
+    for &(rows, framed) in [(&nexts, false), (&boards, true)].iter() {
+        for line in 0..rows[0].len() {
+            for grid in rows.iter() {
+                out.push_str(if framed { "\x1b[0m|" } else { "\x1b[0m " });
+                // the last column is drawn leftmost, as in the window
+                for c in (0..COLS).rev() {
+                    paint(&mut out, grid[line][c], theme);
+                }
+                out.push_str(if framed { "\x1b[0m|  " } else { "\x1b[0m   " });
+            }
+            out.push_str("\x1b[K\r\n");
+        }
+    }
+    for _ in states.iter() {
+        out.push_str(&format!("+{}+  ", "-".repeat(COLS * 2)));
+    }
+    out.push_str("\x1b[K\r\n");
+
+    // who's trading with whom, a column per board, offers to us in yellow
+    for ps in states.iter() {
+        let (label, to_me) = trade_label(ps, game.my_id);
+        let padded = format!("{:<w$}  ", label, w = width);
+        if to_me {
+            out.push_str(&format!("\x1b[33m{}\x1b[0m", padded));
+        }
+        else {
+            out.push_str(&padded);
+        }
+    }
+    out.push_str("\x1b[K\r\n");
+
+    out.push_str(&format!("{}  {}  {}\x1b[K\r\n",
+                          game.rot_clock.label(states),
+                          if mp.connected { "" } else { "disconnected" },
+                          game.status().unwrap_or("")));
+    let lines = game.chat_log.lines();
+    let from = lines.len().saturating_sub(CHAT_LINES);
+    for line in lines[from..].iter() {
+        out.push_str(&format!("{}\x1b[K\r\n", line));
+    }
+    out.push_str("wasd/arrows move  space drop  e/c offer  y/z/x accept/decline/withdraw  \
+                  v vote  p pause  q quit\x1b[K\r\n\x1b[J");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arrows_split_across_reads_still_arrive() {
+        let mut bytes = b"a\x1b".to_vec();
+        assert_eq!(parse_keys(&mut bytes), vec![TermKey::Char('a')]);
+        bytes.extend(b"[");
+        assert_eq!(parse_keys(&mut bytes), vec![]);
+        bytes.extend(b"Cq");
+        assert_eq!(parse_keys(&mut bytes), vec![TermKey::Right, TermKey::Char('q')]);
+        assert!(bytes.is_empty());
+    }
+}